@@ -0,0 +1,436 @@
+use actix_web::{delete, get, post, put, web, HttpMessage, HttpRequest, HttpResponse, Responder, Scope};
+use actix_web::http::StatusCode;
+use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::app::AppFIM;
+use crate::auth::User;
+use crate::search::{EventSearch, EVENT_TABLES};
+use crate::session;
+use crate::sink::EVENT_TYPES;
+
+
+
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 1000;
+
+const EVENT_COLUMNS: &str = "event.id, event.type_event, strftime('%Y-%m-%dT%H:%M:%SZ', event.date_event), path.file_path, from_path.file_path,
+    event.old_sha256, event.new_sha256, event.old_blake3, event.new_blake3, event.mode, event.uid, event.gid, event.operator,
+    coalesce(length(event.diff), 0) > 0";
+
+#[derive(Serialize)]
+struct Event {
+    id: i64,
+    #[serde(rename = "type")]
+    type_event: String,
+    date: String,
+    path: String,
+    from_path: Option<String>,
+    old_sha256: Option<String>,
+    new_sha256: Option<String>,
+    old_blake3: Option<String>,
+    new_blake3: Option<String>,
+    mode: Option<String>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    operator: Option<String>,
+    has_diff: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diff: Option<String>
+}
+
+#[derive(Deserialize)]
+struct EventQuery {
+    #[serde(rename = "type")]
+    type_event: Option<String>,
+    // The file itself, or everything below a directory.
+    path: Option<String>,
+    // Part of the path.
+    contains: Option<String>,
+    // Free text, searched in the paths, hashes and operator.
+    q: Option<String>,
+    // UTC, "2024-05-01T12:00:00Z" or "2024-05-01 12:00:00".
+    since: Option<String>,
+    until: Option<String>,
+    limit: Option<u32>,
+    offset: Option<u32>
+}
+
+#[derive(Deserialize)]
+struct RootQuery {
+    path: PathBuf
+}
+
+#[derive(Deserialize)]
+struct TimelineQuery {
+    path: String
+}
+
+#[derive(Deserialize)]
+struct RootBody {
+    path: String,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>
+}
+
+#[derive(Deserialize)]
+struct RulesBody {
+    path: PathBuf,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>
+}
+
+fn error(status: StatusCode, message: impl ToString) -> HttpResponse {
+    HttpResponse::build(status).json(json!({ "error": message.to_string() }))
+}
+
+fn internal(e: impl ToString) -> HttpResponse {
+    error(StatusCode::INTERNAL_SERVER_ERROR, e)
+}
+
+fn open(data: &web::Data<Arc<Mutex<AppFIM>>>) -> Result<Connection, HttpResponse> {
+    let database = data.get_ref().lock().unwrap().config.storage.database.clone();
+    Connection::open(&database).map_err(internal)
+}
+
+fn event_from_row(row: &Row) -> rusqlite::Result<Event> {
+    Ok(Event {
+        id: row.get(0)?,
+        type_event: row.get(1)?,
+        date: row.get(2)?,
+        path: row.get(3)?,
+        from_path: row.get(4)?,
+        old_sha256: row.get(5)?,
+        new_sha256: row.get(6)?,
+        old_blake3: row.get(7)?,
+        new_blake3: row.get(8)?,
+        mode: row.get::<_, Option<u32>>(9)?.map(|mode| format!("{:04o}", mode & 0o7777)),
+        uid: row.get(10)?,
+        gid: row.get(11)?,
+        operator: row.get(12)?,
+        has_diff: row.get(13)?,
+        diff: None
+    })
+}
+
+// For queries selecting event.diff after EVENT_COLUMNS.
+fn event_with_diff(row: &Row) -> rusqlite::Result<Event> {
+    let mut event = event_from_row(row)?;
+    event.diff = row.get::<_, Option<Vec<u8>>>(14)?
+        .filter(|diff| !diff.is_empty())
+        .map(|diff| String::from_utf8_lossy(&diff).into_owned());
+    Ok(event)
+}
+
+// SQLite reads both forms; anything else comes back NULL.
+fn parse_date(conn: &Connection, name: &str, value: &Option<String>) -> Result<Option<String>, HttpResponse> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None)
+    };
+
+    let parsed: Option<String> = conn.query_row("SELECT datetime(?1)", params![value], |row| row.get(0))
+        .map_err(internal)?;
+    match parsed {
+        Some(parsed) => Ok(Some(parsed)),
+        None => Err(error(StatusCode::BAD_REQUEST, format!("{}: \"{}\" is not a date", name, value)))
+    }
+}
+
+#[get("/events")]
+async fn list_events(data: web::Data<Arc<Mutex<AppFIM>>>, query: web::Query<EventQuery>) -> impl Responder {
+    let conn = match open(&data) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    let type_event = query.type_event.as_ref().map(|type_event| type_event.to_uppercase());
+    if let Some(type_event) = &type_event {
+        if !EVENT_TYPES.contains(&type_event.as_str()) {
+            return error(StatusCode::BAD_REQUEST, format!("type: unknown event type \"{}\"", type_event));
+        }
+    }
+    let (since, until) = match (parse_date(&conn, "since", &query.since), parse_date(&conn, "until", &query.until)) {
+        (Ok(since), Ok(until)) => (since, until),
+        (Err(response), _) | (_, Err(response)) => return response
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+
+    let search = EventSearch {
+        type_event,
+        path: query.path.clone(),
+        contains: query.contains.clone(),
+        since,
+        until,
+        text: query.q.clone(),
+        ..EventSearch::default()
+    };
+    let (filter, values) = search.clause();
+
+    let total: i64 = match conn.query_row(
+        &format!("SELECT count(*) FROM {} {}", EVENT_TABLES, filter),
+        params_from_iter(&values),
+        |row| row.get(0)
+    ) {
+        Ok(total) => total,
+        Err(e) => return internal(e)
+    };
+
+    let events = conn.prepare(&format!("SELECT {} FROM {} {} ORDER BY event.id DESC LIMIT {} OFFSET {}", EVENT_COLUMNS, EVENT_TABLES, filter, limit, offset))
+        .and_then(|mut stmt| {
+            stmt.query_map(params_from_iter(&values), event_from_row)?
+                .collect::<Result<Vec<_>, _>>()
+        });
+
+    match events {
+        Ok(events) => HttpResponse::Ok().json(json!({
+            "events": events,
+            "total": total,
+            "limit": limit,
+            "offset": offset
+        })),
+        Err(e) => internal(e)
+    }
+}
+
+#[get("/events/{id}")]
+async fn get_event(data: web::Data<Arc<Mutex<AppFIM>>>, id: web::Path<i64>) -> impl Responder {
+    let conn = match open(&data) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    let found = conn.query_row(
+        &format!("SELECT {}, event.diff FROM {} WHERE event.id = ?1", EVENT_COLUMNS, EVENT_TABLES),
+        params![id.into_inner()],
+        event_with_diff
+    ).optional();
+
+    match found {
+        Ok(Some(event)) => HttpResponse::Ok().json(event),
+        Ok(None) => error(StatusCode::NOT_FOUND, "no such event"),
+        Err(e) => internal(e)
+    }
+}
+
+// Every event of a file, oldest first, with their diffs. Renames away from
+// the path are included: their from_path is the path asked for.
+#[get("/timeline")]
+async fn get_timeline(data: web::Data<Arc<Mutex<AppFIM>>>, query: web::Query<TimelineQuery>) -> impl Responder {
+    let conn = match open(&data) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    let path_id: Option<i64> = match conn.query_row(
+        "SELECT id FROM path WHERE file_path = ?1",
+        params![query.path],
+        |row| row.get(0)
+    ).optional() {
+        Ok(path_id) => path_id,
+        Err(e) => return internal(e)
+    };
+    let path_id = match path_id {
+        Some(path_id) => path_id,
+        None => return error(StatusCode::NOT_FOUND, "no event recorded for this path")
+    };
+
+    let events = conn.prepare(&format!(
+        "SELECT {}, event.diff FROM {} WHERE event.path_id = ?1 OR event.from_path_id = ?1 ORDER BY event.id",
+        EVENT_COLUMNS, EVENT_TABLES
+    )).and_then(|mut stmt| {
+        stmt.query_map(params![path_id], event_with_diff)?
+            .collect::<Result<Vec<_>, _>>()
+    });
+
+    match events {
+        Ok(events) => HttpResponse::Ok().json(json!({
+            "path": query.path,
+            "events": events
+        })),
+        Err(e) => internal(e)
+    }
+}
+
+#[get("/status")]
+async fn get_status(data: web::Data<Arc<Mutex<AppFIM>>>) -> impl Responder {
+    let conn = match open(&data) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+    let (running, roots) = {
+        let app_fim = data.get_ref().lock().unwrap();
+        (app_fim.state, app_fim.config.roots.clone())
+    };
+
+    let counts = conn.query_row(
+        "SELECT (SELECT count(*) FROM path), (SELECT count(*) FROM event), (SELECT max(id) FROM event),
+            (SELECT strftime('%Y-%m-%dT%H:%M:%SZ', max(date_event)) FROM event)",
+        [],
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, Option<i64>>(2)?, row.get::<_, Option<String>>(3)?))
+    );
+
+    match counts {
+        Ok((paths, events, last_event_id, last_event_date)) => HttpResponse::Ok().json(json!({
+            "running": running,
+            "roots": roots,
+            "paths": paths,
+            "events": events,
+            "last_event_id": last_event_id,
+            "last_event_date": last_event_date
+        })),
+        Err(e) => internal(e)
+    }
+}
+
+#[get("/coverage")]
+async fn get_coverage(data: web::Data<Arc<Mutex<AppFIM>>>) -> impl Responder {
+    let conn = match open(&data) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+    let mut roots: Vec<PathBuf> = data.get_ref().lock().unwrap().config.roots.iter().map(|root| root.path.clone()).collect();
+
+    match session::roots(&conn) {
+        Ok(monitored) => {
+            for root in monitored {
+                if !roots.contains(&root) {
+                    roots.push(root);
+                }
+            }
+        }
+        Err(e) => return internal(e)
+    }
+
+    let mut coverages = Vec::new();
+    for root in roots {
+        match session::coverage(&conn, &root) {
+            Ok(coverage) => coverages.push(json!({
+                "root": root,
+                "percent": coverage.percent(),
+                "total_seconds": coverage.total_seconds,
+                "gap_seconds": coverage.gap_seconds,
+                "spans": coverage.spans
+            })),
+            Err(e) => return internal(e)
+        }
+    }
+
+    HttpResponse::Ok().json(coverages)
+}
+
+// Browser clients read the token to send back in X-CSRF-Token with their
+// POST, PUT and DELETE calls.
+#[get("/session")]
+async fn get_session(req: HttpRequest) -> impl Responder {
+    let user = req.extensions().get::<User>().cloned();
+
+    HttpResponse::Ok().json(json!({
+        "user": user.as_ref().map(|user| user.name.clone()),
+        "csrf_token": user.and_then(|user| user.csrf_token)
+    }))
+}
+
+#[get("/roots")]
+async fn list_roots(data: web::Data<Arc<Mutex<AppFIM>>>) -> impl Responder {
+    HttpResponse::Ok().json(&data.get_ref().lock().unwrap().config.roots)
+}
+
+// Roots and rules only change while the monitor is stopped, as in the web UI.
+#[post("/roots")]
+async fn add_root(data: web::Data<Arc<Mutex<AppFIM>>>, body: web::Json<RootBody>) -> impl Responder {
+    let mut app_fim = data.get_ref().lock().unwrap();
+    if app_fim.state {
+        return error(StatusCode::CONFLICT, "stop monitoring before changing the roots");
+    }
+
+    let path = match app_fim.add_root_with_rules(&body.path, body.include.clone(), body.exclude.clone()) {
+        Ok(path) => path,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e)
+    };
+
+    match app_fim.config.roots.iter().find(|root| root.path == path) {
+        Some(root) => HttpResponse::Created().json(root),
+        None => internal("root not saved")
+    }
+}
+
+#[put("/roots")]
+async fn set_rules(data: web::Data<Arc<Mutex<AppFIM>>>, body: web::Json<RulesBody>) -> impl Responder {
+    let mut app_fim = data.get_ref().lock().unwrap();
+    if app_fim.state {
+        return error(StatusCode::CONFLICT, "stop monitoring before changing the rules");
+    }
+    if !app_fim.config.roots.iter().any(|root| root.path == body.path) {
+        return error(StatusCode::NOT_FOUND, format!("{} is not a watched directory", body.path.display()));
+    }
+
+    match app_fim.set_rules(&body.path, body.include.clone(), body.exclude.clone()) {
+        Ok(()) => HttpResponse::Ok().json(app_fim.config.roots.iter().find(|root| root.path == body.path)),
+        Err(e) => error(StatusCode::BAD_REQUEST, e)
+    }
+}
+
+#[delete("/roots")]
+async fn remove_root(data: web::Data<Arc<Mutex<AppFIM>>>, query: web::Query<RootQuery>) -> impl Responder {
+    let mut app_fim = data.get_ref().lock().unwrap();
+    if app_fim.state {
+        return error(StatusCode::CONFLICT, "stop monitoring before changing the roots");
+    }
+
+    if app_fim.remove_root(Path::new(&query.path)) {
+        HttpResponse::NoContent().finish()
+    } else {
+        error(StatusCode::NOT_FOUND, format!("{} is not a watched directory", query.path.display()))
+    }
+}
+
+#[post("/monitoring/start")]
+async fn start(data: web::Data<Arc<Mutex<AppFIM>>>) -> impl Responder {
+    let started = AppFIM::start(data.get_ref());
+
+    HttpResponse::Ok().json(json!({ "running": true, "changed": started }))
+}
+
+#[post("/monitoring/stop")]
+async fn stop(data: web::Data<Arc<Mutex<AppFIM>>>) -> impl Responder {
+    let stopped = data.get_ref().lock().unwrap().stop();
+
+    HttpResponse::Ok().json(json!({ "running": false, "changed": stopped }))
+}
+
+pub fn scope() -> Scope {
+    // Malformed bodies and query strings get a JSON error too.
+    let json_config = web::JsonConfig::default().error_handler(|e, _| {
+        let response = error(StatusCode::BAD_REQUEST, &e);
+        actix_web::error::InternalError::from_response(e, response).into()
+    });
+    let query_config = web::QueryConfig::default().error_handler(|e, _| {
+        let response = error(StatusCode::BAD_REQUEST, &e);
+        actix_web::error::InternalError::from_response(e, response).into()
+    });
+
+    web::scope("/api/v1")
+        .app_data(json_config)
+        .app_data(query_config)
+        .service(list_events)
+        .service(get_event)
+        .service(get_timeline)
+        .service(get_status)
+        .service(get_coverage)
+        .service(get_session)
+        .service(list_roots)
+        .service(add_root)
+        .service(set_rules)
+        .service(remove_root)
+        .service(start)
+        .service(stop)
+}
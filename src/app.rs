@@ -1,80 +1,580 @@
 extern crate inotify;
 
 use inotify::{Inotify, EventMask, WatchDescriptor};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::fs;
 use std::error::Error;
 use std::io::ErrorKind;
+use std::time::{Duration, Instant};
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use std::os::fd::AsRawFd;
-use std::fs;
-use rusqlite::{Connection, Result};
+use rusqlite::{Connection, OpenFlags, Result};
 
 use crate::watcher::watch_directory_recursive;
-use crate::event_dir::{dir_moved_from, dir_moved_to, dir_delete, dir_create};
-use crate::event_file::{check_rec, check_file};
+use crate::config::{Config, RootConfig};
+use crate::filter::Filters;
+use crate::session;
+use crate::path::check_path;
+use crate::restore::{is_temporary, take_temporary};
+use crate::blob::migrate_last_copies;
+use crate::event_dir::{dir_moved_from, dir_moved_to, dir_rename, dir_delete, dir_create};
+use crate::event_file::{dedupe_paths, strip_overflow_paths, record_overflow, check_rec, check_file, reconcile, has_baseline, compare, rename_file, rename_dir, moved_in_dir, moved_out_dir, Drift};
+
 
 
+// How long the MOVED_FROM half of a move waits for its MOVED_TO before the
+// file is considered moved out of the watched directories.
+const MOVE_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Large enough to drain a burst in a few reads; a single event never exceeds
+// the header plus NAME_MAX + 1 bytes.
+const EVENT_BUFFER_SIZE: usize = 64 * 1024;
+
+enum Migration {
+    Sql(&'static str),
+    Code(fn(&Connection) -> Result<(), Box<dyn Error>>)
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration::Sql("CREATE TABLE event_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        type_event TEXT CHECK (type_event IN ('CREATE', 'DELETE', 'MODIFY', 'MOVED_FROM', 'MOVED_TO', 'RENAME', 'MOVED_OUT', 'MOVED_IN')),
+        date_event TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        diff BLOB,
+        path_id INTEGER NOT NULL,
+        from_path_id INTEGER,
+        FOREIGN KEY (path_id) REFERENCES path(id),
+        FOREIGN KEY (from_path_id) REFERENCES path(id)
+    );
+    INSERT INTO event_new (id, type_event, date_event, diff, path_id)
+        SELECT id, type_event, date_event, diff, path_id FROM event;
+    DROP TABLE event;
+    ALTER TABLE event_new RENAME TO event;"),
+    Migration::Sql("CREATE TABLE event_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        type_event TEXT CHECK (type_event IN ('CREATE', 'DELETE', 'MODIFY', 'MOVED_FROM', 'MOVED_TO', 'RENAME', 'MOVED_OUT', 'MOVED_IN', 'OVERFLOW')),
+        date_event TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        diff BLOB,
+        path_id INTEGER NOT NULL,
+        from_path_id INTEGER,
+        FOREIGN KEY (path_id) REFERENCES path(id),
+        FOREIGN KEY (from_path_id) REFERENCES path(id)
+    );
+    INSERT INTO event_new (id, type_event, date_event, diff, path_id, from_path_id)
+        SELECT id, type_event, date_event, diff, path_id, from_path_id FROM event;
+    DROP TABLE event;
+    ALTER TABLE event_new RENAME TO event;"),
+    Migration::Sql("ALTER TABLE path ADD COLUMN mode INTEGER;
+    ALTER TABLE path ADD COLUMN uid INTEGER;
+    ALTER TABLE path ADD COLUMN gid INTEGER;
+    ALTER TABLE path ADD COLUMN size INTEGER;
+    ALTER TABLE path ADD COLUMN mtime INTEGER;
+    ALTER TABLE path ADD COLUMN ctime INTEGER;
+    ALTER TABLE path ADD COLUMN inode INTEGER;
+    ALTER TABLE path ADD COLUMN nlink INTEGER;
+    CREATE TABLE event_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        type_event TEXT CHECK (type_event IN ('CREATE', 'DELETE', 'MODIFY', 'MOVED_FROM', 'MOVED_TO', 'RENAME', 'MOVED_OUT', 'MOVED_IN', 'OVERFLOW', 'ATTRIB')),
+        date_event TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        diff BLOB,
+        path_id INTEGER NOT NULL,
+        from_path_id INTEGER,
+        FOREIGN KEY (path_id) REFERENCES path(id),
+        FOREIGN KEY (from_path_id) REFERENCES path(id)
+    );
+    INSERT INTO event_new (id, type_event, date_event, diff, path_id, from_path_id)
+        SELECT id, type_event, date_event, diff, path_id, from_path_id FROM event;
+    DROP TABLE event;
+    ALTER TABLE event_new RENAME TO event;"),
+    Migration::Sql("ALTER TABLE path ADD COLUMN sha256 TEXT;
+    ALTER TABLE path ADD COLUMN blake3 TEXT;
+    ALTER TABLE event ADD COLUMN old_sha256 TEXT;
+    ALTER TABLE event ADD COLUMN new_sha256 TEXT;
+    ALTER TABLE event ADD COLUMN old_blake3 TEXT;
+    ALTER TABLE event ADD COLUMN new_blake3 TEXT;"),
+    Migration::Sql("CREATE TABLE blob (
+        sha256 TEXT PRIMARY KEY,
+        data BLOB NOT NULL,
+        size INTEGER NOT NULL,
+        refcount INTEGER NOT NULL DEFAULT 0
+    );"),
+    Migration::Code(migrate_last_copies),
+    Migration::Sql("ALTER TABLE path DROP COLUMN last_copy;"),
+    Migration::Sql("CREATE TABLE event_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        type_event TEXT CHECK (type_event IN ('CREATE', 'DELETE', 'MODIFY', 'MOVED_FROM', 'MOVED_TO', 'RENAME', 'MOVED_OUT', 'MOVED_IN', 'OVERFLOW', 'ATTRIB', 'RESTORE')),
+        date_event TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        diff BLOB,
+        path_id INTEGER NOT NULL,
+        from_path_id INTEGER,
+        old_sha256 TEXT,
+        new_sha256 TEXT,
+        old_blake3 TEXT,
+        new_blake3 TEXT,
+        mode INTEGER,
+        uid INTEGER,
+        gid INTEGER,
+        operator TEXT,
+        FOREIGN KEY (path_id) REFERENCES path(id),
+        FOREIGN KEY (from_path_id) REFERENCES path(id)
+    );
+    INSERT INTO event_new (id, type_event, date_event, diff, path_id, from_path_id, old_sha256, new_sha256, old_blake3, new_blake3)
+        SELECT id, type_event, date_event, diff, path_id, from_path_id, old_sha256, new_sha256, old_blake3, new_blake3 FROM event;
+    DROP TABLE event;
+    ALTER TABLE event_new RENAME TO event;"),
+    Migration::Sql("CREATE TABLE event_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        type_event TEXT CHECK (type_event IN ('CREATE', 'DELETE', 'MODIFY', 'MOVED_FROM', 'MOVED_TO', 'RENAME', 'MOVED_OUT', 'MOVED_IN', 'OVERFLOW', 'ATTRIB', 'RESTORE', 'OFFLINE_CREATE', 'OFFLINE_DELETE', 'OFFLINE_MODIFY')),
+        date_event TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        diff BLOB,
+        path_id INTEGER NOT NULL,
+        from_path_id INTEGER,
+        old_sha256 TEXT,
+        new_sha256 TEXT,
+        old_blake3 TEXT,
+        new_blake3 TEXT,
+        mode INTEGER,
+        uid INTEGER,
+        gid INTEGER,
+        operator TEXT,
+        FOREIGN KEY (path_id) REFERENCES path(id),
+        FOREIGN KEY (from_path_id) REFERENCES path(id)
+    );
+    INSERT INTO event_new (id, type_event, date_event, diff, path_id, from_path_id, old_sha256, new_sha256, old_blake3, new_blake3, mode, uid, gid, operator)
+        SELECT id, type_event, date_event, diff, path_id, from_path_id, old_sha256, new_sha256, old_blake3, new_blake3, mode, uid, gid, operator FROM event;
+    DROP TABLE event;
+    ALTER TABLE event_new RENAME TO event;"),
+    Migration::Sql("CREATE TABLE session (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        status TEXT NOT NULL CHECK (status IN ('RUNNING', 'STOPPED', 'CRASHED')),
+        started_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        heartbeat_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        stopped_at TIMESTAMP,
+        pid INTEGER NOT NULL
+    );
+    CREATE TABLE session_root (
+        session_id INTEGER NOT NULL,
+        root TEXT NOT NULL,
+        FOREIGN KEY (session_id) REFERENCES session(id)
+    );
+    CREATE INDEX session_root_root ON session_root (root);"),
+    Migration::Sql("CREATE TABLE outbox (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        sink TEXT NOT NULL,
+        event_id INTEGER NOT NULL,
+        payload TEXT NOT NULL,
+        status TEXT NOT NULL DEFAULT 'PENDING' CHECK (status IN ('PENDING', 'DELIVERED', 'FAILED')),
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL,
+        last_error TEXT,
+        created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        delivered_at TIMESTAMP,
+        FOREIGN KEY (event_id) REFERENCES event(id)
+    );
+    CREATE INDEX outbox_due ON outbox (sink, status, next_attempt_at);"),
+    Migration::Sql("CREATE TABLE account (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE login_session (
+        token_sha256 TEXT PRIMARY KEY,
+        account_id INTEGER NOT NULL,
+        created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        expires_at TIMESTAMP NOT NULL,
+        FOREIGN KEY (account_id) REFERENCES account(id)
+    );"),
+    Migration::Sql("DELETE FROM login_session;
+    ALTER TABLE login_session ADD COLUMN csrf_token TEXT NOT NULL DEFAULT '';"),
+    Migration::Code(dedupe_paths),
+    Migration::Sql("CREATE UNIQUE INDEX path_file_path ON path (file_path);
+    CREATE INDEX event_date ON event (date_event);
+    CREATE INDEX event_type_date ON event (type_event, date_event);
+    CREATE INDEX event_path ON event (path_id);
+    CREATE INDEX event_from_path ON event (from_path_id);"),
+    Migration::Code(strip_overflow_paths),
+    Migration::Sql("ALTER TABLE session ADD COLUMN host TEXT;"),
+];
+
+// Each migration and its version number in one transaction, so that a
+// failure leaves the database as it was.
+fn apply(
+    conn: &Connection,
+    migrations: &[Migration]
+) -> Result<(), Box<dyn Error>> {
+
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, migration) in migrations.iter().enumerate().skip(version) {
+        let transaction = conn.unchecked_transaction()?;
+        match migration {
+            Migration::Sql(sql) => transaction.execute_batch(sql)?,
+            Migration::Code(code) => code(&transaction)?
+        }
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+struct PendingMove {
+    path: PathBuf,
+    is_dir: bool,
+    since: Instant,
+    // The temporary file of a restore, which records its own event.
+    restore: bool
+}
 
 pub struct AppFIM {
     pub state: bool,
-    pub path_from_web: Vec<PathBuf>
+    pub config: Config,
+    pub config_path: PathBuf
 }
 
 impl AppFIM {
-    pub fn new() -> Self {
+    pub fn new(config: Config, config_path: PathBuf) -> Self {
         Self {
             state: false,
-            path_from_web: Vec::new()
-        }
-    }
-
-    fn init_db() -> Result<(), Box<dyn Error>> {
-        if !fs::metadata("database.db").is_ok() {
-            fs::File::create("database.db")?;
-            
-            let conn = Connection::open("database.db")?;
-            conn.execute(
-                "CREATE TABLE IF NOT EXISTS path (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    file_path TEXT NOT NULL,
-                    last_copy BLOB NOT NULL
-                );",
-                []
-            )?;
-
-            conn.execute(
-                "CREATE TABLE IF NOT EXISTS event (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    type_event TEXT CHECK (type_event IN ('CREATE', 'DELETE', 'MODIFY', 'MOVED_FROM', 'MOVED_TO')),
-                    date_event TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
-                    diff BLOB,
-                    path_id INTEGER NOT NULL,
-                    FOREIGN KEY (path_id) REFERENCES path(id)
-                );",
-                []
-            )?;
+            config,
+            config_path
+        }
+    }
+
+    // Keeps the roots changed from the web UI across restarts. A failure is
+    // only reported: the change still applies to the running monitor.
+    pub fn save_config(&self) {
+        if let Err(e) = self.config.save(&self.config_path) {
+            println!("Impossible to save the configuration to {:?}: {}", self.config_path, e);
+        }
+    }
+
+    // Adds a directory to watch, replacing the roots it contains.
+    pub fn add_root(&mut self, path: &str) -> Result<PathBuf, String> {
+        let desired_path = fs::canonicalize(path).map_err(|e| format!("{}: {}", path, e))?;
+        if check_path(path).is_err() {
+            return Err(format!("{} contains the working directory of the monitor", desired_path.display()));
+        }
+        if !desired_path.is_dir() {
+            return Err(format!("{} is not a directory", desired_path.display()));
+        }
+        if let Some(file) = self.config.own_file_below(&self.config_path, &desired_path) {
+            return Err(format!("{} contains {}, written by the monitor", desired_path.display(), file.display()));
+        }
+
+        if self.config.roots.iter().any(|root| root.path == desired_path) {
+            return Ok(desired_path);
+        }
+        if let Some(root) = self.config.roots.iter().find(|root| desired_path.starts_with(&root.path)) {
+            return Err(format!("{} is already watched through {}", desired_path.display(), root.path.display()));
+        }
+
+        self.config.roots.retain(|root| !root.path.starts_with(&desired_path));
+        self.config.roots.push(RootConfig::new(desired_path.clone()));
+        self.save_config();
+
+        Ok(desired_path)
+    }
+
+    // The rules are checked first: once added, the roots the new one
+    // replaced are gone.
+    pub fn add_root_with_rules(
+        &mut self,
+        path: &str,
+        include: Vec<String>,
+        exclude: Vec<String>
+    ) -> Result<PathBuf, String> {
+
+        let root = RootConfig { include, exclude, ..RootConfig::new(PathBuf::from(path)) };
+        if let Err(e) = Filters::new(std::slice::from_ref(&root)) {
+            return Err(format!("{}: {}", path, e));
+        }
+
+        let path = self.add_root(path)?;
+        if !root.include.is_empty() || !root.exclude.is_empty() {
+            self.set_rules(&path, root.include, root.exclude)?;
+        }
+
+        Ok(path)
+    }
+
+    pub fn remove_root(&mut self, path: &Path) -> bool {
+        let count = self.config.roots.len();
+        self.config.roots.retain(|root| root.path != path);
+        if self.config.roots.len() == count {
+            return false;
+        }
+
+        self.save_config();
+        true
+    }
+
+    pub fn set_rules(
+        &mut self,
+        path: &Path,
+        include: Vec<String>,
+        exclude: Vec<String>
+    ) -> Result<(), String> {
+
+        let root = match self.config.roots.iter_mut().find(|root| root.path == path) {
+            Some(root) => root,
+            None => return Err(format!("{} is not a watched directory", path.display()))
+        };
+
+        let mut updated = root.clone();
+        updated.include = include;
+        updated.exclude = exclude;
+        if let Err(e) = Filters::new(std::slice::from_ref(&updated)) {
+            return Err(format!("{}: {}", path.display(), e));
+        }
+        *root = updated;
+        self.save_config();
+
+        Ok(())
+    }
+
+    // Returns false when the monitor was already running.
+    pub fn start(app_fim_mutex: &Arc<Mutex<AppFIM>>) -> bool {
+        let mut app_fim = app_fim_mutex.lock().unwrap();
+        if app_fim.state {
+            return false;
+        }
+        app_fim.state = true;
+
+        println!("Launch of the program...");
+
+        let app_fim_clone = Arc::clone(app_fim_mutex);
+        let config = app_fim.config.clone();
+        thread::spawn(move || {
+            AppFIM::app(app_fim_clone, config).expect("Impossible to start app");
+        });
+
+        true
+    }
+
+    pub fn stop(&mut self) -> bool {
+        if !self.state {
+            return false;
+        }
+        self.state = false;
+
+        println!("Stopping the program...");
+
+        true
+    }
+
+    pub fn init_db(database: &Path) -> Result<(), Box<dyn Error>> {
+        Self::migrate(&Connection::open(database)?)
+    }
+
+    // Creates the schema, or brings an older one up to date.
+    pub fn migrate(conn: &Connection) -> Result<(), Box<dyn Error>> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS path (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                file_path TEXT NOT NULL,
+                last_copy BLOB NOT NULL
+            );",
+            []
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS event (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                type_event TEXT CHECK (type_event IN ('CREATE', 'DELETE', 'MODIFY', 'MOVED_FROM', 'MOVED_TO')),
+                date_event TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                diff BLOB,
+                path_id INTEGER NOT NULL,
+                FOREIGN KEY (path_id) REFERENCES path(id)
+            );",
+            []
+        )?;
+
+        apply(conn, MIGRATIONS)
+    }
+
+    // Records the current state of every root without any event, as the
+    // monitor does when it starts.
+    pub fn baseline(config: &Config) -> Result<(), Box<dyn Error>> {
+        Self::init_db(&config.storage.database)?;
+        let filters = Filters::new(&config.roots)?;
+        let conn = Connection::open(&config.storage.database)?;
+
+        for root in &config.roots {
+            println!("Référence : {:?}", root.path);
+            check_rec(&conn, &root.path, &filters)?;
         }
 
         Ok(())
     }
 
+    // Records as events everything that changed since the last run.
+    pub fn scan(config: &Config) -> Result<(), Box<dyn Error>> {
+        Self::init_db(&config.storage.database)?;
+        let filters = Filters::new(&config.roots)?;
+        let conn = Connection::open(&config.storage.database)?;
+
+        for root in &config.roots {
+            println!("Analyse : {:?}", root.path);
+            Self::catch_up(&conn, &root.path, &filters)?;
+        }
+
+        Ok(())
+    }
+
+    // Whatever changed while nothing was watching is recorded with the
+    // OFFLINE_ events; a root seen for the first time only gets its baseline.
+    fn catch_up(
+        conn: &Connection,
+        root: &Path,
+        filters: &Filters
+    ) -> Result<(), Box<dyn Error>> {
+
+        if has_baseline(conn, root)? {
+            reconcile(conn, root, filters, true)
+        } else {
+            check_rec(conn, root, filters)
+        }
+    }
+
+    // For the commands that only read: the schema is brought up to date when
+    // the monitor or the web interface starts, never on the way.
+    pub fn open_db(database: &Path) -> Result<Connection, Box<dyn Error>> {
+        Self::open_existing(database, OpenFlags::SQLITE_OPEN_READ_WRITE)
+    }
+
+    fn open_existing(
+        database: &Path,
+        flags: OpenFlags
+    ) -> Result<Connection, Box<dyn Error>> {
+
+        if !database.exists() {
+            return Err(format!("{}: no database, run init first", database.display()).into());
+        }
+        let conn = Connection::open_with_flags(database, flags)?;
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version < MIGRATIONS.len() {
+            return Err(format!("{}: database from an older version, run scan once to upgrade it", database.display()).into());
+        }
+        if version > MIGRATIONS.len() {
+            return Err(format!("{}: database from a newer version", database.display()).into());
+        }
+
+        Ok(conn)
+    }
+
+    // Compares every root with the database without writing to it.
+    pub fn check(config: &Config) -> Result<Drift, Box<dyn Error>> {
+        let filters = Filters::new(&config.roots)?;
+        let conn = Self::open_existing(&config.storage.database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+        let mut drift = Drift::default();
+        for root in &config.roots {
+            compare(&conn, &root.path, &filters, &mut drift)?;
+        }
+
+        Ok(drift)
+    }
+
+    fn flush_pending_moves(
+        conn: &Connection,
+        inotify: &Inotify,
+        pending_moves: &mut HashMap<u32, PendingMove>,
+        watched_dirs: &mut HashMap<WatchDescriptor, PathBuf>,
+        all: bool
+    ) -> Result<(), Box<dyn Error>> {
+
+        let expired: Vec<u32> = pending_moves.iter()
+            .filter(|(_, pending)| all || pending.since.elapsed() >= MOVE_TIMEOUT)
+            .map(|(cookie, _)| *cookie)
+            .collect();
+
+        for cookie in expired {
+            let pending = match pending_moves.remove(&cookie) {
+                Some(pending) => pending,
+                None => continue
+            };
+
+            if pending.restore {
+                continue;
+            }
+            if pending.is_dir {
+                println!("Dossier sorti : {:?}", pending.path);
+                dir_moved_from(inotify, &pending.path, watched_dirs)?;
+                moved_out_dir(conn, &pending.path)?;
+            } else {
+                println!("Fichier sorti : {:?}", pending.path);
+                check_file(conn, &pending.path, "MOVED_OUT")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The kernel dropped events, so neither the watch paths nor the pending
+    // moves can be trusted: rebuild the watches from scratch and compare every
+    // root against the database.
+    fn recover_overflow(
+        conn: &Connection,
+        inotify: &Inotify,
+        roots: &[RootConfig],
+        filters: &Filters,
+        pending_moves: &mut HashMap<u32, PendingMove>,
+        watched_dirs: &mut HashMap<WatchDescriptor, PathBuf>
+    ) -> Result<(), Box<dyn Error>> {
+
+        pending_moves.clear();
+        for wd in watched_dirs.keys() {
+            let _ = inotify.watches().remove(wd.clone());
+        }
+        watched_dirs.clear();
+
+        for root in roots {
+            let path = &root.path;
+            println!("Débordement de la file : {:?}", path);
+            record_overflow(conn, path)?;
+            let _ = watch_directory_recursive(inotify, path, watched_dirs, filters);
+            reconcile(conn, path, filters, false)?;
+        }
+
+        Ok(())
+    }
+
+    // Every run is recorded as a session, so that the periods when nothing
+    // was watching can be told apart from quiet ones.
     pub fn app(
         app_fim_mutex: Arc<Mutex<AppFIM>>,
-        path_from_web: Vec<PathBuf>
+        config: Config
     ) -> Result<(), Box<dyn Error>> {
-        Self::init_db()?;
+        let database = &config.storage.database;
+        Self::init_db(database)?;
+        let session_id = session::start(&Connection::open(database)?, &config.roots)?;
+
+        let result = Self::monitor(app_fim_mutex, &config);
+
+        let status = if result.is_ok() { "STOPPED" } else { "CRASHED" };
+        session::stop(&Connection::open(database)?, session_id, status)?;
+        result
+    }
+
+    fn monitor(
+        app_fim_mutex: Arc<Mutex<AppFIM>>,
+        config: &Config
+    ) -> Result<(), Box<dyn Error>> {
+        let database = &config.storage.database;
+        let filters = Filters::new(&config.roots)?;
 
         let mut inotify = Inotify::init().expect("Failed to initialize inotify");
         let mut watched_dirs: HashMap<WatchDescriptor, PathBuf> = HashMap::new();
-        for path in &path_from_web {
-            match watch_directory_recursive(&inotify, path, &mut watched_dirs) {
-                _ => ()
-            }
-            let conn = Connection::open("database.db")?;
-            match check_rec(&conn, path) {
-                _ => ()
+        let mut pending_moves: HashMap<u32, PendingMove> = HashMap::new();
+        for root in &config.roots {
+            let _ = watch_directory_recursive(&inotify, &root.path, &mut watched_dirs, &filters);
+            if root.rescan_on_start {
+                let conn = Connection::open(database)?;
+                let _ = Self::catch_up(&conn, &root.path, &filters);
             }
         }
 
@@ -82,46 +582,113 @@ impl AppFIM {
         fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).expect("Failed to set non-blocking mode");
 
         println!("OK!");
-        println!("");
-        
-        let mut buffer = [0; 4096];
+        println!();
+
+        let mut buffer = [0; EVENT_BUFFER_SIZE];
         loop {
             match inotify.read_events(&mut buffer) {
                 Ok(events) => {
-                    let conn = Connection::open("database.db")?;
+                    let conn = Connection::open(database)?;
+                    let mut overflow = false;
+
+                    for event in events {
+                        if event.mask.contains(EventMask::Q_OVERFLOW) {
+                            overflow = true;
+                            continue;
+                        }
+
+                        if event.mask.contains(EventMask::IGNORED) {
+                            watched_dirs.remove(&event.wd);
+                            continue;
+                        }
 
-                    for event in events {                                
                         let name = match event.name {
                             Some(name) => name,
                             None => continue
                         };
-                                
+
                         let mut complete_path = match watched_dirs.get(&event.wd) {
                             Some(complete_path) => complete_path,
                             None => continue
                         }.clone();
                         complete_path.push(name);
-                                
-                        if event.mask.contains(EventMask::ISDIR) {
+                        let is_dir = event.mask.contains(EventMask::ISDIR);
+
+                        // A restore of this process renames its temporary
+                        // file over the target: neither the file nor the
+                        // rename is recorded, only the RESTORE event.
+                        if is_temporary(&complete_path) {
+                            if event.mask.contains(EventMask::MOVED_FROM) {
+                                take_temporary(&complete_path);
+                                pending_moves.insert(event.cookie, PendingMove {
+                                    path: complete_path,
+                                    is_dir,
+                                    since: Instant::now(),
+                                    restore: true
+                                });
+                            }
+                            continue;
+                        }
+
+                        // Directories that are only walked to reach included
+                        // files still need their watches kept up to date.
+                        if filters.is_excluded(&complete_path, is_dir) || (!is_dir && !filters.is_recorded(&complete_path, false)) {
+                            continue;
+                        }
+
+                        if event.mask.contains(EventMask::MOVED_FROM) {
+                            pending_moves.insert(event.cookie, PendingMove {
+                                path: complete_path,
+                                is_dir,
+                                since: Instant::now(),
+                                restore: false
+                            });
+                            continue;
+                        }
+
+                        if event.mask.contains(EventMask::MOVED_TO) {
+                            match pending_moves.remove(&event.cookie) {
+                                Some(pending) if pending.restore => {}
+                                Some(pending) if is_dir => {
+                                    println!("Dossier renommé : {:?} -> {:?}", pending.path, complete_path);
+                                    dir_rename(&pending.path, &complete_path, &mut watched_dirs)?;
+                                    rename_dir(&conn, &pending.path, &complete_path, &filters)?;
+                                }
+                                Some(pending) => {
+                                    println!("Fichier renommé : {:?} -> {:?}", pending.path, complete_path);
+                                    rename_file(&conn, &pending.path, &complete_path)?;
+                                }
+                                None if is_dir => {
+                                    println!("Dossier entré : {:?}", complete_path);
+                                    dir_moved_to(&inotify, &complete_path, &mut watched_dirs, &filters)?;
+                                    moved_in_dir(&conn, &complete_path, &filters)?;
+                                }
+                                None => {
+                                    println!("Fichier entré : {:?}", complete_path);
+                                    check_file(&conn, &complete_path, "MOVED_IN")?;
+                                }
+                            }
+                            continue;
+                        }
+
+                        if is_dir {
                             let flag = EventMask::ISDIR ^ event.mask;
                             match flag {
                                 EventMask::CREATE => {
                                     println!("Dossier créé : {:?}", complete_path);
-                                    dir_create(&inotify, &complete_path, &mut watched_dirs)?;
+                                    dir_create(&inotify, &complete_path, &mut watched_dirs, &filters)?;
+                                    if filters.is_recorded(&complete_path, true) {
+                                        check_file(&conn, &complete_path, "MAYBE_ATTRIB")?;
+                                    }
                                     //check_rec(&complete_path, &mut path_json)?;
                                 }
                                 EventMask::DELETE => {
                                     println!("Dossier supprimé : {:?}", complete_path);
                                     dir_delete(&complete_path, &mut watched_dirs)?;
                                 }
-                                EventMask::MOVED_FROM => {
-                                    println!("Dossier from : {:?}", complete_path);
-                                    dir_moved_from(&inotify, &complete_path, &mut watched_dirs)?;
-                                }
-                                EventMask::MOVED_TO => {
-                                    println!("Dossier to : {:?}", complete_path);
-                                    dir_moved_to(&inotify, &complete_path, &mut watched_dirs)?;
-                                    //check_rec(&complete_path, &mut path_json)?;
+                                EventMask::ATTRIB if filters.is_recorded(&complete_path, true) => {
+                                    println!("Dossier attributs : {:?}", complete_path);
+                                    check_file(&conn, &complete_path, "ATTRIB")?;
                                 }
                                 _ => {}
                             }
@@ -139,31 +706,120 @@ impl AppFIM {
                                     println!("Fichier crée : {:?}", complete_path);
                                     check_file(&conn, &complete_path, "CREATE")?;
                                 }
-                                EventMask::MOVED_FROM => {
-                                    println!("Fichier moved from : {:?}", complete_path);
-                                    check_file(&conn, &complete_path, "MOVED_FROM")?;
-                                }
-                                EventMask::MOVED_TO => {
-                                    println!("Fichier moved to : {:?}", complete_path);
-                                    check_file(&conn, &complete_path, "MOVED_TO")?;
+                                EventMask::ATTRIB => {
+                                    println!("Fichier attributs : {:?}", complete_path);
+                                    check_file(&conn, &complete_path, "ATTRIB")?;
                                 }
                             _ => {}
                             }
                         }
                     }
+
+                    if overflow {
+                        Self::recover_overflow(&conn, &inotify, &config.roots, &filters, &mut pending_moves, &mut watched_dirs)?;
+                    } else {
+                        Self::flush_pending_moves(&conn, &inotify, &mut pending_moves, &mut watched_dirs, false)?;
+                    }
+                    session::beat(&conn)?;
                 },
                 Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if !pending_moves.is_empty() {
+                        let conn = Connection::open(database)?;
+                        Self::flush_pending_moves(&conn, &inotify, &mut pending_moves, &mut watched_dirs, false)?;
+                    }
+
+                    if session::beat_due() {
+                        session::beat(&Connection::open(database)?)?;
+                    }
+
                     let app_fim = app_fim_mutex.lock().unwrap();
-                    if app_fim.state == false {
+                    if !app_fim.state {
+                        let conn = Connection::open(database)?;
+                        Self::flush_pending_moves(&conn, &inotify, &mut pending_moves, &mut watched_dirs, true)?;
+
                         println!("OK!");
-                        println!("");
+                        println!();
                         break Ok(());
                     }
                 },
-                Err(_) => {
-                    break Ok(());
+                Err(e) => {
+                    break Err(e.into());
                 }
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn reading_never_creates_nor_upgrades_the_database() {
+        let database = testing::directory("open-db").join("database.db");
+
+        let missing = AppFIM::open_db(&database).unwrap_err();
+        assert!(missing.to_string().contains("run init first"));
+        assert!(!database.exists());
+
+        AppFIM::init_db(&database).unwrap();
+        AppFIM::open_db(&database).unwrap();
+
+        let conn = Connection::open(&database).unwrap();
+        conn.execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len() - 1)).unwrap();
+        let older = AppFIM::open_db(&database).unwrap_err();
+        assert!(older.to_string().contains("older version"));
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, MIGRATIONS.len() - 1);
+
+        conn.execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len() + 1)).unwrap();
+        assert!(AppFIM::open_db(&database).unwrap_err().to_string().contains("newer version"));
+    }
+
+    #[test]
+    fn a_failed_migration_is_rolled_back() {
+        let conn = Connection::open_in_memory().unwrap();
+        let migrations = [
+            Migration::Sql("CREATE TABLE a (x INTEGER);"),
+            Migration::Sql("CREATE TABLE b (x INTEGER); INSERT INTO a VALUES (1); INSERT INTO missing VALUES (1);"),
+        ];
+
+        assert!(apply(&conn, &migrations).is_err());
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, 1);
+        assert!(conn.is_autocommit());
+        let rows: i64 = conn.query_row("SELECT count(*) FROM a", [], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 0);
+        assert!(conn.prepare("SELECT * FROM b").is_err());
+
+        // Fixed, it runs again from where it stopped.
+        let migrations = [Migration::Sql("CREATE TABLE a (x INTEGER);"), Migration::Sql("CREATE TABLE b (x INTEGER);")];
+        apply(&conn, &migrations).unwrap();
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, 2);
+    }
+
+    #[test]
+    fn bad_rules_leave_the_roots_as_they_were() {
+        let dir = testing::directory("add-root-rules");
+        fs::create_dir_all(dir.join("srv/www")).unwrap();
+        fs::create_dir_all(dir.join("srv/data")).unwrap();
+        let config_path = dir.join("fim.toml");
+        let mut config = Config::default();
+        config.storage.database = dir.join("fim.db");
+        config.roots = vec![RootConfig::new(dir.join("srv/www")), RootConfig::new(dir.join("srv/data"))];
+        let mut app_fim = AppFIM::new(config, config_path.clone());
+
+        let path = dir.join("srv").to_string_lossy().into_owned();
+        assert!(app_fim.add_root_with_rules(&path, Vec::new(), vec![String::from("{a")]).is_err());
+        assert_eq!(app_fim.config.roots.len(), 2);
+        assert!(!config_path.exists());
+
+        let root = app_fim.add_root_with_rules(&path, Vec::new(), vec![String::from("*.log")]).unwrap();
+        assert_eq!(root, dir.join("srv"));
+        assert_eq!(app_fim.config.roots.len(), 1);
+        assert_eq!(app_fim.config.roots[0].exclude, ["*.log"]);
+        assert_eq!(Config::load(&config_path).unwrap().roots[0].exclude, ["*.log"]);
+    }
+}
@@ -0,0 +1,533 @@
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::{header, Method};
+use actix_web::web;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use base64::Engine;
+use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::error::Error;
+use std::time::{Duration, Instant};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::json;
+
+use crate::app::AppFIM;
+use crate::hash::Digests;
+
+
+
+pub const COOKIE_NAME: &str = "fim_session";
+
+const SESSION_HOURS: i64 = 12;
+
+// Checked when the user does not exist, so that a wrong name takes as long
+// as a wrong password.
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+// Scripts send their Basic credentials with every call; each check costs
+// an argon2 hash on the web worker, so a success is remembered for a while.
+// Keyed by the SHA-256 of the header, along with the password hash it was
+// checked against so that a new password or a deleted user takes effect at
+// once.
+const BASIC_CACHE_TTL: Duration = Duration::from_secs(60);
+
+static BASIC_CACHE: OnceLock<Mutex<HashMap<String, (String, Instant)>>> = OnceLock::new();
+
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+// The signed-in user, added to the request by `check`. Only a session
+// cookie comes with a CSRF token; Basic credentials cannot be forged by
+// another site.
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub csrf_token: Option<String>
+}
+
+fn hash_password(password: &str) -> Result<String, Box<dyn Error>> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| e.to_string())?;
+
+    Ok(hash.to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false
+    }
+}
+
+// Without an account the password is checked against a dummy hash anyway.
+fn verify_account(password: &str, hash: Option<&str>) -> bool {
+    match hash {
+        Some(hash) => verify_password(password, hash),
+        None => {
+            let dummy = DUMMY_HASH.get_or_init(|| hash_password(&Alphanumeric.sample_string(&mut OsRng, 20)).unwrap_or_default());
+            verify_password(password, dummy);
+            false
+        }
+    }
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn token_sha256(token: &str) -> String {
+    Digests::of(token.as_bytes()).sha256
+}
+
+pub fn add_user(
+    conn: &Connection,
+    name: &str,
+    password: &str
+) -> Result<(), Box<dyn Error>> {
+
+    if name.is_empty() || password.is_empty() {
+        return Err("the name and the password cannot be empty".into());
+    }
+    conn.execute(
+        "INSERT INTO account (name, password_hash) VALUES (?1, ?2)",
+        params![name, hash_password(password)?],
+    ).map_err(|e| match e {
+        rusqlite::Error::SqliteFailure(error, _) if error.code == rusqlite::ErrorCode::ConstraintViolation => {
+            format!("user {} already exists", name).into()
+        }
+        e => Box::<dyn Error>::from(e)
+    })?;
+
+    Ok(())
+}
+
+// Also signs the user out everywhere.
+pub fn set_password(
+    conn: &Connection,
+    name: &str,
+    password: &str
+) -> Result<(), Box<dyn Error>> {
+
+    if password.is_empty() {
+        return Err("the password cannot be empty".into());
+    }
+    let updated = conn.execute(
+        "UPDATE account SET password_hash = ?1 WHERE name = ?2",
+        params![hash_password(password)?, name],
+    )?;
+    if updated == 0 {
+        return Err(format!("no user {}", name).into());
+    }
+    conn.execute(
+        "DELETE FROM login_session WHERE account_id = (SELECT id FROM account WHERE name = ?1)",
+        params![name],
+    )?;
+
+    Ok(())
+}
+
+pub fn delete_user(
+    conn: &Connection,
+    name: &str
+) -> Result<(), Box<dyn Error>> {
+
+    conn.execute(
+        "DELETE FROM login_session WHERE account_id = (SELECT id FROM account WHERE name = ?1)",
+        params![name],
+    )?;
+    if conn.execute("DELETE FROM account WHERE name = ?1", params![name])? == 0 {
+        return Err(format!("no user {}", name).into());
+    }
+
+    Ok(())
+}
+
+pub fn list_users(
+    conn: &Connection
+) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+
+    let mut stmt = conn.prepare("SELECT name, strftime('%Y-%m-%d %H:%M:%S', created_at) FROM account ORDER BY name")?;
+    let users = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(users)
+}
+
+// Without any account nobody could sign in, so the first start creates an
+// admin with a random password and returns it to be shown once.
+pub fn bootstrap(
+    conn: &Connection
+) -> Result<Option<String>, Box<dyn Error>> {
+
+    let count: i64 = conn.query_row("SELECT count(*) FROM account", [], |row| row.get(0))?;
+    if count > 0 {
+        return Ok(None);
+    }
+
+    let password = Alphanumeric.sample_string(&mut OsRng, 20);
+    add_user(conn, "admin", &password)?;
+
+    Ok(Some(password))
+}
+
+pub fn login(
+    conn: &Connection,
+    name: &str,
+    password: &str
+) -> Result<Option<String>, Box<dyn Error>> {
+
+    let account: Option<(i64, String)> = conn.query_row(
+        "SELECT id, password_hash FROM account WHERE name = ?1",
+        params![name],
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).optional()?;
+
+    let account_id = match account {
+        Some((id, hash)) if verify_account(password, Some(&hash)) => id,
+        Some(_) => return Ok(None),
+        None => {
+            verify_account(password, None);
+            return Ok(None);
+        }
+    };
+
+    let token = random_token();
+
+    conn.execute("DELETE FROM login_session WHERE expires_at <= CURRENT_TIMESTAMP", [])?;
+    conn.execute(
+        "INSERT INTO login_session (token_sha256, account_id, expires_at, csrf_token) VALUES (?1, ?2, datetime('now', ?3), ?4)",
+        params![token_sha256(&token), account_id, format!("+{} hours", SESSION_HOURS), random_token()],
+    )?;
+
+    Ok(Some(token))
+}
+
+pub fn logout(
+    conn: &Connection,
+    token: &str
+) -> Result<(), Box<dyn Error>> {
+
+    conn.execute("DELETE FROM login_session WHERE token_sha256 = ?1", params![token_sha256(token)])?;
+
+    Ok(())
+}
+
+fn session_user(
+    conn: &Connection,
+    token: &str
+) -> Result<Option<User>, Box<dyn Error>> {
+
+    let user = conn.query_row(
+        "SELECT account.name, login_session.csrf_token FROM login_session INNER JOIN account ON login_session.account_id = account.id
+        WHERE login_session.token_sha256 = ?1 AND login_session.expires_at > CURRENT_TIMESTAMP",
+        params![token_sha256(token)],
+        |row| Ok(User { name: row.get(0)?, csrf_token: Some(row.get(1)?) })
+    ).optional()?;
+
+    Ok(user)
+}
+
+// Long-lived responses check now and then that their session still exists.
+pub fn session_active(
+    conn: &Connection,
+    token: &str
+) -> bool {
+
+    matches!(session_user(conn, token), Ok(Some(_)))
+}
+
+// Scripts calling the API can send their credentials with each request
+// instead of keeping a cookie.
+fn basic_user(
+    conn: &Connection,
+    authorization: &str
+) -> Result<Option<User>, Box<dyn Error>> {
+
+    let encoded = match authorization.strip_prefix("Basic ") {
+        Some(encoded) => encoded.trim(),
+        None => return Ok(None)
+    };
+    let decoded = match base64::engine::general_purpose::STANDARD.decode(encoded).ok().and_then(|bytes| String::from_utf8(bytes).ok()) {
+        Some(decoded) => decoded,
+        None => return Ok(None)
+    };
+    let (name, password) = match decoded.split_once(':') {
+        Some(credentials) => credentials,
+        None => return Ok(None)
+    };
+
+    let hash: Option<String> = conn.query_row(
+        "SELECT password_hash FROM account WHERE name = ?1",
+        params![name],
+        |row| row.get(0)
+    ).optional()?;
+    let user = User { name: name.to_string(), csrf_token: None };
+
+    let key = token_sha256(authorization);
+    let cache = BASIC_CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+    if let (Some(hash), Some((checked, at))) = (&hash, cache.lock().unwrap().get(&key)) {
+        if checked == hash && at.elapsed() < BASIC_CACHE_TTL {
+            return Ok(Some(user));
+        }
+    }
+
+    if !verify_account(password, hash.as_deref()) {
+        return Ok(None);
+    }
+    let mut cache = cache.lock().unwrap();
+    cache.retain(|_, (_, at)| at.elapsed() < BASIC_CACHE_TTL);
+    cache.insert(key, (hash.unwrap_or_default(), Instant::now()));
+
+    Ok(Some(user))
+}
+
+pub fn session_cookie(token: &str) -> Cookie<'static> {
+    Cookie::build(COOKIE_NAME, token.to_string())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(actix_web::cookie::time::Duration::hours(SESSION_HOURS))
+        .finish()
+}
+
+pub fn removal_cookie() -> Cookie<'static> {
+    let mut cookie = Cookie::build(COOKIE_NAME, "").path("/").finish();
+    cookie.make_removal();
+    cookie
+}
+
+fn csrf_matches(user: &User, sent: Option<&str>) -> bool {
+    match (&user.csrf_token, sent) {
+        (None, _) => true,
+        (Some(expected), Some(sent)) => !expected.is_empty() && expected.len() == sent.len()
+            && expected.bytes().zip(sent.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0,
+        (Some(_), None) => false
+    }
+}
+
+// For the handlers of the HTML forms, which carry the token in a hidden
+// `csrf` field.
+pub fn verify_csrf(req: &HttpRequest, sent: Option<&String>) -> Result<(), HttpResponse> {
+    match req.extensions().get::<User>() {
+        Some(user) if csrf_matches(user, sent.map(String::as_str)) => Ok(()),
+        _ => Err(HttpResponse::Forbidden().body("missing or invalid CSRF token"))
+    }
+}
+
+pub fn csrf_token(req: &HttpRequest) -> String {
+    req.extensions().get::<User>()
+        .and_then(|user| user.csrf_token.clone())
+        .unwrap_or_default()
+}
+
+// Browsers send Origin with cross-site POSTs, and Basic credentials they
+// remember would go along with them.
+fn same_origin(req: &ServiceRequest) -> bool {
+    let origin = match req.headers().get(header::ORIGIN).and_then(|value| value.to_str().ok()) {
+        Some(origin) => origin,
+        None => return true
+    };
+    let host = req.headers().get(header::HOST).and_then(|value| value.to_str().ok());
+
+    match (origin.split_once("://"), host) {
+        (Some((_, origin_host)), Some(host)) => origin_host.eq_ignore_ascii_case(host),
+        _ => false
+    }
+}
+
+fn is_public(path: &str) -> bool {
+    path == "/login"
+}
+
+fn authenticate(req: &ServiceRequest) -> Result<Option<User>, Box<dyn Error>> {
+    let database = match req.app_data::<web::Data<Arc<Mutex<AppFIM>>>>() {
+        Some(data) => data.get_ref().lock().unwrap().config.storage.database.clone(),
+        None => return Ok(None)
+    };
+    let conn = Connection::open(database)?;
+
+    if let Some(cookie) = req.cookie(COOKIE_NAME) {
+        if let Some(user) = session_user(&conn, cookie.value())? {
+            return Ok(Some(user));
+        }
+    }
+    if req.path().starts_with("/api/") {
+        if let Some(authorization) = req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok()) {
+            return basic_user(&conn, authorization);
+        }
+    }
+
+    Ok(None)
+}
+
+// Lets the request through with its user attached, or returns what to send
+// instead: a redirect to the login page, or a 401 for the API.
+pub fn check(req: &ServiceRequest) -> Result<(), HttpResponse> {
+    let unsafe_method = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if unsafe_method && !same_origin(req) {
+        return Err(HttpResponse::Forbidden().body("cross-origin request refused"));
+    }
+    if is_public(req.path()) {
+        return Ok(());
+    }
+
+    match authenticate(req) {
+        Ok(Some(user)) => {
+            // Forms check their token themselves; API calls made with the
+            // session cookie send it in a header.
+            if unsafe_method && req.path().starts_with("/api/") {
+                let sent = req.headers().get(CSRF_HEADER).and_then(|value| value.to_str().ok());
+                if !csrf_matches(&user, sent) {
+                    return Err(HttpResponse::Forbidden().json(json!({ "error": "missing or invalid CSRF token" })));
+                }
+            }
+            req.extensions_mut().insert(user);
+            Ok(())
+        }
+        Ok(None) if req.path().starts_with("/api/") => Err(
+            HttpResponse::Unauthorized()
+                .append_header((header::WWW_AUTHENTICATE, "Basic realm=\"fim\""))
+                .json(json!({ "error": "authentication required" }))
+        ),
+        Ok(None) => Err(HttpResponse::Found().append_header(("Location", "/login")).finish()),
+        Err(e) => Err(HttpResponse::InternalServerError().body(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use crate::testing;
+
+    fn session(csrf_token: &str) -> User {
+        User { name: String::from("alice"), csrf_token: Some(csrf_token.to_string()) }
+    }
+
+    fn form_request(user: Option<User>) -> HttpRequest {
+        let req = TestRequest::post().uri("/add").to_http_request();
+        if let Some(user) = user {
+            req.extensions_mut().insert(user);
+        }
+        req
+    }
+
+    fn basic(name: &str, password: &str) -> String {
+        format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", name, password)))
+    }
+
+    fn basic_name(conn: &Connection, authorization: &str) -> Option<String> {
+        basic_user(conn, authorization).unwrap().map(|user| user.name)
+    }
+
+    #[test]
+    fn basic_credentials() {
+        let conn = testing::database();
+        add_user(&conn, "alice", "secret").unwrap();
+
+        assert_eq!(basic_name(&conn, &basic("alice", "secret")), Some(String::from("alice")));
+        assert_eq!(basic_name(&conn, &basic("alice", "wrong")), None);
+        assert_eq!(basic_name(&conn, &basic("bob", "secret")), None);
+        assert_eq!(basic_name(&conn, "Basic not base64!"), None);
+        assert_eq!(basic_name(&conn, &format!("Basic {}", base64::engine::general_purpose::STANDARD.encode("alice"))), None);
+        assert_eq!(basic_name(&conn, "Bearer abc"), None);
+    }
+
+    #[test]
+    fn remembered_credentials_follow_the_account() {
+        let conn = testing::database();
+        add_user(&conn, "carol", "first").unwrap();
+        let authorization = basic("carol", "first");
+        assert!(basic_name(&conn, &authorization).is_some());
+        assert!(basic_name(&conn, &authorization).is_some());
+
+        set_password(&conn, "carol", "second").unwrap();
+        assert_eq!(basic_name(&conn, &authorization), None);
+        assert!(basic_name(&conn, &basic("carol", "second")).is_some());
+
+        delete_user(&conn, "carol").unwrap();
+        assert_eq!(basic_name(&conn, &basic("carol", "second")), None);
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let conn = testing::database();
+        add_user(&conn, "dave", "secret").unwrap();
+        assert_eq!(login(&conn, "dave", "wrong").unwrap(), None);
+        assert_eq!(login(&conn, "nobody", "secret").unwrap(), None);
+
+        let token = login(&conn, "dave", "secret").unwrap().unwrap();
+        let user = session_user(&conn, &token).unwrap().unwrap();
+        assert_eq!(user.name, "dave");
+        assert_eq!(user.csrf_token.unwrap().len(), 64);
+        assert!(session_active(&conn, &token));
+        assert!(!session_active(&conn, "unknown"));
+
+        conn.execute("UPDATE login_session SET expires_at = datetime('now', '-1 second')", []).unwrap();
+        assert!(session_user(&conn, &token).unwrap().is_none());
+
+        // Expired sessions are cleared at the next sign-in.
+        let other = login(&conn, "dave", "secret").unwrap().unwrap();
+        let sessions: i64 = conn.query_row("SELECT count(*) FROM login_session", [], |row| row.get(0)).unwrap();
+        assert_eq!(sessions, 1);
+        assert!(session_active(&conn, &other));
+
+        logout(&conn, &other).unwrap();
+        assert!(!session_active(&conn, &other));
+    }
+
+    #[test]
+    fn csrf_tokens_must_match_exactly() {
+        let token = "f".repeat(64);
+        assert!(csrf_matches(&session(&token), Some(&token)));
+        assert!(!csrf_matches(&session(&token), None));
+        assert!(!csrf_matches(&session(&token), Some("")));
+        assert!(!csrf_matches(&session(&token), Some(&"f".repeat(63))));
+        assert!(!csrf_matches(&session(&token), Some(&format!("{}e", "f".repeat(63)))));
+        assert!(!csrf_matches(&session(""), Some("")));
+
+        // Basic credentials are not sent by a form of another site.
+        let basic = User { name: String::from("alice"), csrf_token: None };
+        assert!(csrf_matches(&basic, None));
+    }
+
+    #[test]
+    fn forms_need_the_token_of_their_session() {
+        let token = "0123".repeat(16);
+
+        assert!(verify_csrf(&form_request(Some(session(&token))), Some(&token)).is_ok());
+        let refused = [
+            verify_csrf(&form_request(Some(session(&token))), Some(&"3210".repeat(16))),
+            verify_csrf(&form_request(Some(session(&token))), None),
+            verify_csrf(&form_request(None), Some(&token))
+        ];
+        for result in refused {
+            assert_eq!(result.unwrap_err().status(), StatusCode::FORBIDDEN);
+        }
+        assert_eq!(csrf_token(&form_request(Some(session(&token)))), token);
+        assert_eq!(csrf_token(&form_request(None)), "");
+    }
+
+    #[test]
+    fn cross_origin_writes_are_refused() {
+        let post = |origin: &str| TestRequest::post()
+            .uri("/api/v1/roots")
+            .insert_header((header::HOST, "fim.example:6077"))
+            .insert_header((header::ORIGIN, origin))
+            .to_srv_request();
+
+        assert_eq!(check(&post("https://evil.example")).unwrap_err().status(), StatusCode::FORBIDDEN);
+        assert_eq!(check(&post("null")).unwrap_err().status(), StatusCode::FORBIDDEN);
+        // Same origin goes on to authentication, which has no user here.
+        assert_eq!(check(&post("http://FIM.example:6077")).unwrap_err().status(), StatusCode::UNAUTHORIZED);
+    }
+}
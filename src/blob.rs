@@ -0,0 +1,102 @@
+use std::error::Error;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::hash::Digests;
+
+
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+// Stores the content under its SHA-256, or takes one more reference on the
+// existing blob when the same content is already known.
+pub fn put(
+    conn: &Connection,
+    data: &[u8],
+    digests: &Digests
+) -> Result<(), Box<dyn Error>> {
+
+    let updated = conn.execute(
+        "UPDATE blob SET refcount = refcount + 1 WHERE sha256 = ?1",
+        params![digests.sha256],
+    )?;
+
+    if updated == 0 {
+        let compressed = zstd::bulk::compress(data, COMPRESSION_LEVEL)?;
+        conn.execute(
+            "INSERT INTO blob (sha256, data, size, refcount) VALUES (?1, ?2, ?3, 1)",
+            params![digests.sha256, compressed, data.len() as i64],
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn acquire(
+    conn: &Connection,
+    sha256: &str
+) -> Result<(), Box<dyn Error>> {
+
+    conn.execute(
+        "UPDATE blob SET refcount = refcount + 1 WHERE sha256 = ?1",
+        params![sha256],
+    )?;
+
+    Ok(())
+}
+
+pub fn release(
+    conn: &Connection,
+    sha256: &str
+) -> Result<(), Box<dyn Error>> {
+
+    conn.execute(
+        "UPDATE blob SET refcount = refcount - 1 WHERE sha256 = ?1",
+        params![sha256],
+    )?;
+    conn.execute(
+        "DELETE FROM blob WHERE sha256 = ?1 AND refcount <= 0",
+        params![sha256],
+    )?;
+
+    Ok(())
+}
+
+pub fn get(
+    conn: &Connection,
+    sha256: &str
+) -> Result<Vec<u8>, Box<dyn Error>> {
+
+    let mut stmt = conn.prepare("SELECT data, size FROM blob WHERE sha256 = ?1")?;
+    let blob: Option<(Vec<u8>, i64)> = stmt.query_row(params![sha256], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    }).optional()?;
+
+    match blob {
+        Some((data, size)) => Ok(zstd::bulk::decompress(&data, size as usize)?),
+        None => Err(format!("missing blob {}", sha256).into())
+    }
+}
+
+// Moves the copies that older databases kept inline in path.last_copy into
+// the blob store.
+pub fn migrate_last_copies(
+    conn: &Connection
+) -> Result<(), Box<dyn Error>> {
+
+    let mut stmt = conn.prepare("SELECT id, last_copy FROM path")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+    })?;
+
+    for row in rows {
+        let (id, last_copy) = row?;
+        let digests = Digests::of(&last_copy);
+        put(conn, &last_copy, &digests)?;
+        conn.execute(
+            "UPDATE path SET sha256 = ?1, blake3 = ?2 WHERE id = ?3",
+            params![digests.sha256, digests.blake3, id],
+        )?;
+    }
+
+    Ok(())
+}
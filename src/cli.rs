@@ -0,0 +1,518 @@
+use std::sync::{Arc, Mutex};
+use std::env;
+use std::fs;
+use std::thread;
+use std::process;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::error::Error;
+use clap::{Parser, Subcommand};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+use std::os::fd::AsRawFd;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension};
+
+use crate::web::start_web;
+use crate::app::AppFIM;
+use crate::auth;
+use crate::restore::restore;
+use crate::search::{EventSearch, EVENT_TABLES};
+use crate::config::{config_path, Config, RootConfig};
+use crate::event_file::{history, Drift};
+use crate::session;
+use crate::sink;
+use crate::webhook;
+
+
+
+#[derive(Parser)]
+#[command(name = "fileintegritymonitoring", version, about = "File integrity monitoring")]
+pub struct Cli {
+    #[arg(long, global = true, help = "Configuration file [default: $FIM_CONFIG or /etc/fim/fim.toml]")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Create the database, add roots to the configuration and record their baseline")]
+    Init {
+        roots: Vec<PathBuf>
+    },
+    #[command(about = "Record what changed since the last run as events, then exit")]
+    Scan,
+    #[command(
+        about = "Compare the roots with the baseline without recording anything",
+        after_help = "Exit status: 0 when nothing drifted, 1 when something did, 2 on error."
+    )]
+    Check,
+    #[command(about = "Monitor the roots until SIGINT or SIGTERM, without the web interface")]
+    Watch,
+    #[command(about = "Start the web interface (default)")]
+    Serve,
+    #[command(about = "Show the configuration and the content of the database")]
+    Status,
+    #[command(about = "Show when each root was monitored and the gaps in between")]
+    Coverage {
+        root: Option<PathBuf>
+    },
+    #[command(about = "List the latest events")]
+    Events {
+        #[arg(long, default_value_t = 50)]
+        limit: u32,
+        #[arg(long, help = "Only events on this file or below this directory")]
+        path: Option<PathBuf>,
+        #[arg(long = "type", help = "Only events of this type (MODIFY, DELETE...)")]
+        type_event: Option<String>
+    },
+    #[command(about = "Print the diff recorded by an event")]
+    Diff {
+        id: i64
+    },
+    #[command(about = "List the versions of a file")]
+    History {
+        path: PathBuf
+    },
+    #[command(about = "Write a recorded version back to disk")]
+    Restore {
+        event_id: i64,
+        #[arg(long, help = "Restore the version from before the event")]
+        before: bool
+    },
+    #[command(about = "Manage the accounts of the web interface")]
+    User {
+        #[command(subcommand)]
+        action: UserCommand
+    }
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    #[command(about = "Create an account, reading its password from the terminal or stdin")]
+    Add {
+        name: String
+    },
+    #[command(about = "Change the password of an account and sign it out everywhere")]
+    Passwd {
+        name: String
+    },
+    #[command(about = "Delete an account")]
+    Del {
+        name: String
+    },
+    #[command(about = "List the accounts")]
+    List
+}
+
+fn operator() -> String {
+    let user = env::var("SUDO_USER")
+        .or_else(|_| env::var("USER"))
+        .unwrap_or_else(|_| String::from("unknown"));
+
+    format!("cli ({})", user)
+}
+
+// Without echo when typed in a terminal; a pipe gives the first line.
+fn read_password() -> Result<String, Box<dyn Error>> {
+    eprint!("Password: ");
+    io::stderr().flush()?;
+
+    let stdin = io::stdin();
+    let saved = tcgetattr(stdin.as_raw_fd()).ok();
+    if let Some(saved) = &saved {
+        let mut silent = saved.clone();
+        silent.local_flags.remove(LocalFlags::ECHO);
+        tcsetattr(stdin.as_raw_fd(), SetArg::TCSANOW, &silent)?;
+    }
+
+    let mut password = String::new();
+    let read = stdin.read_line(&mut password);
+    if let Some(saved) = &saved {
+        tcsetattr(stdin.as_raw_fd(), SetArg::TCSANOW, saved)?;
+        eprintln!();
+    }
+    read?;
+
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn user(
+    conn: &Connection,
+    action: UserCommand
+) -> Result<(), Box<dyn Error>> {
+
+    match action {
+        UserCommand::Add { name } => {
+            auth::add_user(conn, &name, &read_password()?)?;
+            println!("user {} created", name);
+        }
+        UserCommand::Passwd { name } => {
+            auth::set_password(conn, &name, &read_password()?)?;
+            println!("password of {} changed", name);
+        }
+        UserCommand::Del { name } => {
+            auth::delete_user(conn, &name)?;
+            println!("user {} deleted", name);
+        }
+        UserCommand::List => {
+            for (name, created_at) in auth::list_users(conn)? {
+                println!("{:<20} {}", name, created_at);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn init(
+    mut config: Config,
+    config_path: &Path,
+    roots: &[PathBuf]
+) -> Result<(), Box<dyn Error>> {
+
+    for root in roots {
+        let root = fs::canonicalize(root)
+            .map_err(|e| format!("{}: {}", root.display(), e))?;
+
+        if !config.roots.iter().any(|existing| existing.path == root) {
+            config.roots.push(RootConfig::new(root));
+        }
+    }
+    config.validate(config_path)?;
+
+    if !roots.is_empty() || !config_path.exists() {
+        config.save(config_path)?;
+        println!("Configuration écrite : {:?}", config_path);
+    }
+
+    AppFIM::baseline(&config)?;
+    println!("Base de données prête : {:?}", config.storage.database);
+
+    Ok(())
+}
+
+fn print_section(
+    title: &str,
+    mark: char,
+    entries: &[(&PathBuf, &[String])]
+) {
+    if entries.is_empty() {
+        return;
+    }
+
+    println!("{} ({}):", title, entries.len());
+    for (path, details) in entries {
+        println!("  {} {}", mark, path.display());
+        for detail in details.iter() {
+            println!("      {}", detail);
+        }
+    }
+    println!();
+}
+
+fn print_drift(
+    drift: &Drift
+) {
+    let added: Vec<_> = drift.added.iter().map(|path| (path, &[][..])).collect();
+    let removed: Vec<_> = drift.removed.iter().map(|path| (path, &[][..])).collect();
+    let changed: Vec<_> = drift.changed.iter().map(|(path, details)| (path, details.as_slice())).collect();
+    let metadata: Vec<_> = drift.metadata.iter().map(|(path, details)| (path, details.as_slice())).collect();
+
+    print_section("Added", '+', &added);
+    print_section("Removed", '-', &removed);
+    print_section("Changed", '~', &changed);
+    print_section("Metadata changed", '~', &metadata);
+
+    println!(
+        "Summary: {} added, {} removed, {} changed, {} metadata changed",
+        drift.added.len(),
+        drift.removed.len(),
+        drift.changed.len(),
+        drift.metadata.len()
+    );
+}
+
+fn watch(
+    config: Config,
+    config_path: PathBuf
+) -> Result<(), Box<dyn Error>> {
+
+    // Blocked before any thread starts so that only the waiting thread below
+    // receives them.
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.thread_block()?;
+
+    let app_fim_mutex = Arc::new(Mutex::new(AppFIM::new(config.clone(), config_path)));
+    app_fim_mutex.lock().unwrap().state = true;
+
+    let app_fim_clone = Arc::clone(&app_fim_mutex);
+    thread::spawn(move || {
+        if let Ok(signal) = signals.wait() {
+            println!("{:?} reçu, arrêt de la surveillance...", signal);
+            app_fim_clone.lock().unwrap().state = false;
+        }
+    });
+
+    println!("Launch of the program...");
+    AppFIM::app(app_fim_mutex, config)
+}
+
+fn status(
+    config: &Config,
+    config_path: &Path
+) -> Result<(), Box<dyn Error>> {
+
+    let present = if config_path.exists() { "" } else { " (absent, defaults)" };
+    println!("config:    {}{}", config_path.display(), present);
+    println!("web:       {}", config.web.bind);
+    println!("roots:     {}", config.roots.len());
+    for root in &config.roots {
+        println!("  {} (rescan_on_start: {})", root.path.display(), root.rescan_on_start);
+        if !root.include.is_empty() {
+            println!("    include: {}", root.include.join(" "));
+        }
+        if !root.exclude.is_empty() {
+            println!("    exclude: {}", root.exclude.join(" "));
+        }
+    }
+
+    let database = &config.storage.database;
+    if !database.exists() {
+        println!("database:  {} (not initialized, run init)", database.display());
+        return Ok(());
+    }
+    println!("database:  {}", database.display());
+
+    let conn = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let paths: i64 = conn.query_row("SELECT count(*) FROM path", [], |row| row.get(0))?;
+    let events: i64 = conn.query_row("SELECT count(*) FROM event", [], |row| row.get(0))?;
+    let last_event: Option<String> = conn.query_row(
+        "SELECT strftime('%Y-%m-%d %H:%M:%S', date_event) FROM event ORDER BY id DESC LIMIT 1",
+        [],
+        |row| row.get(0)
+    ).optional()?;
+    println!("paths:     {}", paths);
+    println!("events:    {} (last: {})", events, last_event.as_deref().unwrap_or("-"));
+
+    let blobs: Option<(i64, Option<i64>)> = conn.query_row(
+        "SELECT count(*), sum(size) FROM blob",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).ok();
+    if let Some((count, size)) = blobs {
+        println!("versions:  {} ({} bytes before compression)", count, size.unwrap_or(0));
+    }
+
+    Ok(())
+}
+
+fn print_coverage(
+    conn: &Connection,
+    config: &Config,
+    root: Option<&Path>
+) -> Result<(), Box<dyn Error>> {
+
+    let roots = match root {
+        Some(root) => vec![fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf())],
+        None => {
+            let mut roots: Vec<PathBuf> = config.roots.iter().map(|root| root.path.clone()).collect();
+            for root in session::roots(conn)? {
+                if !roots.contains(&root) {
+                    roots.push(root);
+                }
+            }
+            roots
+        }
+    };
+
+    for root in roots {
+        let coverage = session::coverage(conn, &root)?;
+        println!("{}", root.display());
+        if coverage.spans.is_empty() {
+            println!("  never monitored");
+            println!();
+            continue;
+        }
+
+        for span in &coverage.spans {
+            println!("  {:<8} {}  ->  {}  ({})", span.status, span.start, span.end, session::format_duration(span.seconds));
+        }
+        println!(
+            "  {:.1}% monitored over {}, {} without monitoring",
+            coverage.percent(),
+            session::format_duration(coverage.total_seconds),
+            session::format_duration(coverage.gap_seconds)
+        );
+        println!();
+    }
+
+    Ok(())
+}
+
+fn events(
+    conn: &Connection,
+    limit: u32,
+    path: Option<&Path>,
+    type_event: Option<&str>
+) -> Result<(), Box<dyn Error>> {
+
+    let search = EventSearch {
+        type_event: type_event.map(str::to_uppercase),
+        path: path.map(|path| path.to_string_lossy().into_owned()),
+        ..EventSearch::default()
+    };
+    let (filter, values) = search.clause();
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT event.id, strftime('%Y-%m-%d %H:%M:%S', event.date_event), event.type_event, path.file_path, from_path.file_path
+        FROM {} {} ORDER BY event.id DESC LIMIT {}",
+        EVENT_TABLES, filter, limit
+    ))?;
+    let rows = stmt.query_map(params_from_iter(&values), |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, Option<String>>(4)?
+        ))
+    })?;
+
+    for row in rows {
+        let (id, date_event, type_event, file_path, from_path) = row?;
+        match from_path {
+            Some(from_path) => println!("{:>6}  {}  {:<10} {} <- {}", id, date_event, type_event, file_path, from_path),
+            None => println!("{:>6}  {}  {:<10} {}", id, date_event, type_event, file_path)
+        }
+    }
+
+    Ok(())
+}
+
+fn diff(
+    conn: &Connection,
+    id: i64
+) -> Result<(), Box<dyn Error>> {
+
+    let (file_path, type_event, patch, old_sha256, new_sha256): (String, String, Option<Vec<u8>>, Option<String>, Option<String>) = conn.query_row(
+        "SELECT path.file_path, event.type_event, event.diff, event.old_sha256, event.new_sha256
+        FROM event INNER JOIN path ON event.path_id = path.id WHERE event.id = ?1",
+        params![id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+    ).optional()?.ok_or_else(|| format!("no event {}", id))?;
+
+    println!("# {} {} {}", id, type_event, file_path);
+    println!("# sha256 {} -> {}", old_sha256.as_deref().unwrap_or("-"), new_sha256.as_deref().unwrap_or("-"));
+    io::stdout().write_all(&patch.unwrap_or_default())?;
+
+    Ok(())
+}
+
+fn print_history(
+    conn: &Connection,
+    path: &Path
+) -> Result<(), Box<dyn Error>> {
+
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let versions = history(conn, &path)?;
+    if versions.is_empty() {
+        return Err(format!("no history for {}", path.display()).into());
+    }
+
+    for version in versions {
+        let sha256: String = version.sha256.as_deref().unwrap_or("-").chars().take(12).collect();
+        match version.from_path {
+            Some(from_path) if version.type_event == "RENAME" => println!("{:>6}  {}  {:<12} {:<12} from {}", version.event_id, version.date_event, version.type_event, sha256, from_path),
+            _ => println!("{:>6}  {}  {:<12} {}", version.event_id, version.date_event, version.type_event, sha256)
+        }
+    }
+
+    Ok(())
+}
+
+// Nothing drifted, as the exit status of check. It records nothing, so it
+// needs none of the sinks.
+fn check(
+    config_path: &Path
+) -> Result<bool, Box<dyn Error>> {
+
+    let config = Config::load(config_path)
+        .map_err(|e| format!("invalid configuration: {}", e))?;
+    let drift = AppFIM::check(&config)?;
+    print_drift(&drift);
+
+    Ok(drift.is_empty())
+}
+
+pub fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let config_path = cli.config.unwrap_or_else(config_path);
+    let command = cli.command.unwrap_or(Command::Serve);
+
+    // Any error of check, the configuration included, must not read as a
+    // drift.
+    if matches!(command, Command::Check) {
+        process::exit(match check(&config_path) {
+            Ok(true) => 0,
+            Ok(false) => 1,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                2
+            }
+        });
+    }
+
+    let config = Config::load(&config_path)
+        .map_err(|e| format!("invalid configuration: {}", e))?;
+    let database = config.storage.database.clone();
+    sink::init(&config)?;
+
+    let records_events = matches!(command, Command::Scan | Command::Restore { .. });
+    if matches!(command, Command::Serve | Command::Watch) {
+        if let Some(webhook) = sink::webhook_config() {
+            AppFIM::init_db(&database)?;
+            webhook::spawn(database.clone(), webhook.clone());
+        }
+    }
+
+    let result = match command {
+        Command::Init { roots } => init(config, &config_path, &roots),
+        Command::Scan => AppFIM::scan(&config),
+        Command::Check => unreachable!(),
+        Command::Watch => watch(config, config_path),
+        Command::Serve => {
+            AppFIM::init_db(&database)?;
+            if let Some(password) = auth::bootstrap(&Connection::open(&database)?)? {
+                println!("Aucun compte : utilisateur admin créé avec le mot de passe {}", password);
+                println!("Changez-le avec : fileintegritymonitoring user passwd admin");
+            }
+            start_web(Arc::new(Mutex::new(AppFIM::new(config, config_path))))?;
+            Ok(())
+        }
+        Command::Status => status(&config, &config_path),
+        Command::Coverage { root } => print_coverage(&AppFIM::open_db(&database)?, &config, root.as_deref()),
+        Command::Events { limit, path, type_event } => events(&AppFIM::open_db(&database)?, limit, path.as_deref(), type_event.as_deref()),
+        Command::Diff { id } => diff(&AppFIM::open_db(&database)?, id),
+        Command::History { path } => print_history(&AppFIM::open_db(&database)?, &path),
+        Command::Restore { event_id, before } => {
+            AppFIM::init_db(&database)?;
+            restore(&Connection::open(&database)?, event_id, before, &operator())?;
+            Ok(())
+        }
+        Command::User { action } => {
+            AppFIM::init_db(&database)?;
+            user(&Connection::open(&database)?, action)
+        }
+    };
+
+    // One-shot commands send what they recorded before exiting; anything
+    // that fails stays in the outbox for the next run.
+    if let (true, Some(webhook)) = (records_events, sink::webhook_config()) {
+        webhook::deliver_pending(&Connection::open(&database)?, webhook)?;
+    }
+    sink::flush();
+
+    result
+}
@@ -0,0 +1,430 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::error::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::path::check_path;
+use crate::filter::Filters;
+use crate::sink::EventFilter;
+use crate::syslog::facility_code;
+
+
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/fim/fim.toml";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RootConfig {
+    pub path: PathBuf,
+    // Compare the tree with the database when monitoring starts.
+    #[serde(default = "default_true")]
+    pub rescan_on_start: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageConfig {
+    #[serde(default = "default_database")]
+    pub database: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_log: Option<EventLogConfig>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventLogConfig {
+    pub path: PathBuf,
+    // The file is rotated when it reaches either limit, into path.1 to
+    // path.<keep>.
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: u64,
+    #[serde(default = "default_max_age_hours")]
+    pub max_age_hours: u64,
+    #[serde(default = "default_keep")]
+    pub keep: u32
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebConfig {
+    #[serde(default = "default_bind")]
+    pub bind: String
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    // Signs the body with HMAC-SHA256 in X-FIM-Signature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    // Only these event types, and only paths matching these globs; empty
+    // means everything.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub types: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paths: Vec<String>,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogTransport {
+    Udp,
+    Tcp,
+    Unix
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFormat {
+    Rfc5424,
+    Cef,
+    Leef
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogConfig {
+    pub transport: SyslogTransport,
+    // host:port, or the socket path for unix.
+    pub address: String,
+    #[serde(default = "default_syslog_format")]
+    pub format: SyslogFormat,
+    #[serde(default = "default_facility")]
+    pub facility: String,
+    #[serde(default = "default_app_name")]
+    pub app_name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub types: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paths: Vec<String>
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertingConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<WebhookConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub syslog: Option<SyslogConfig>
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub web: WebConfig,
+    #[serde(default)]
+    pub roots: Vec<RootConfig>,
+    #[serde(default)]
+    pub alerting: AlertingConfig
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_database() -> PathBuf {
+    PathBuf::from("database.db")
+}
+
+fn default_bind() -> String {
+    String::from("127.0.0.1:6077")
+}
+
+fn default_max_attempts() -> u32 {
+    12
+}
+
+fn default_timeout_seconds() -> u64 {
+    10
+}
+
+fn default_max_size_mb() -> u64 {
+    100
+}
+
+fn default_max_age_hours() -> u64 {
+    24
+}
+
+fn default_keep() -> u32 {
+    7
+}
+
+fn default_syslog_format() -> SyslogFormat {
+    SyslogFormat::Rfc5424
+}
+
+fn default_facility() -> String {
+    String::from("auth")
+}
+
+fn default_app_name() -> String {
+    String::from("fim")
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            database: default_database(),
+            event_log: None
+        }
+    }
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        Self {
+            bind: default_bind()
+        }
+    }
+}
+
+impl RootConfig {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            rescan_on_start: true,
+            include: Vec::new(),
+            exclude: Vec::new()
+        }
+    }
+}
+
+// $FIM_CONFIG, or /etc/fim/fim.toml.
+pub fn config_path() -> PathBuf {
+    match env::var_os("FIM_CONFIG") {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(DEFAULT_CONFIG_PATH)
+    }
+}
+
+impl Config {
+    // A missing file is not an error: the defaults are used and the file is
+    // created the first time roots are changed from the web UI.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                println!("No configuration at {:?}, using defaults", path);
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(format!("{}: {}", path.display(), e).into())
+        };
+
+        let config: Config = toml::from_str(&content)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        config.validate(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        Ok(config)
+    }
+
+    // The files the monitor writes: a root holding one of them would record
+    // its own writes over and over.
+    fn own_files(&self, path: &Path) -> Vec<PathBuf> {
+        let mut files = vec![path.to_path_buf(), self.storage.database.clone()];
+        if let Some(event_log) = &self.storage.event_log {
+            files.push(event_log.path.clone());
+        }
+
+        files.iter().map(|file| {
+            let parent = file.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            match (fs::canonicalize(parent), file.file_name()) {
+                (Ok(parent), Some(name)) => parent.join(name),
+                _ => file.clone()
+            }
+        }).collect()
+    }
+
+    // The first file of the monitor found under the directory, if any.
+    pub fn own_file_below(&self, path: &Path, dir: &Path) -> Option<PathBuf> {
+        let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        self.own_files(path).into_iter().find(|file| file.starts_with(&dir))
+    }
+
+    pub fn validate(&self, path: &Path) -> Result<(), String> {
+        if self.web.bind.parse::<SocketAddr>().is_err() {
+            return Err(format!("web.bind: \"{}\" is not an address such as 127.0.0.1:6077", self.web.bind));
+        }
+
+        if let Some(parent) = self.storage.database.parent() {
+            if !parent.as_os_str().is_empty() && !parent.is_dir() {
+                return Err(format!("storage.database: directory {} does not exist", parent.display()));
+            }
+        }
+
+        if let Some(event_log) = &self.storage.event_log {
+            if let Some(parent) = event_log.path.parent() {
+                if !parent.as_os_str().is_empty() && !parent.is_dir() {
+                    return Err(format!("storage.event_log.path: directory {} does not exist", parent.display()));
+                }
+            }
+            if event_log.max_size_mb == 0 || event_log.max_age_hours == 0 || event_log.keep == 0 {
+                return Err(String::from("storage.event_log: max_size_mb, max_age_hours and keep must be at least 1"));
+            }
+        }
+
+        for (index, root) in self.roots.iter().enumerate() {
+            let name = format!("roots[{}].path", index);
+
+            if !root.path.is_absolute() {
+                return Err(format!("{}: {} is not an absolute path", name, root.path.display()));
+            }
+            if !root.path.is_dir() {
+                return Err(format!("{}: {} is not an existing directory", name, root.path.display()));
+            }
+            if check_path(&root.path.to_string_lossy()).is_err() {
+                return Err(format!("{}: {} contains the working directory of the monitor", name, root.path.display()));
+            }
+            if let Some(file) = self.own_file_below(path, &root.path) {
+                return Err(format!("{}: {} contains {}, written by the monitor", name, root.path.display(), file.display()));
+            }
+
+            for (other_index, other) in self.roots.iter().enumerate() {
+                if other_index != index && root.path.starts_with(&other.path) {
+                    return Err(format!(
+                        "{}: {} is already watched through roots[{}].path",
+                        name,
+                        root.path.display(),
+                        other_index
+                    ));
+                }
+            }
+        }
+
+        if let Err(e) = Filters::new(&self.roots) {
+            return Err(format!("roots: {}", e));
+        }
+
+        if let Some(webhook) = &self.alerting.webhook {
+            if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                return Err(format!("alerting.webhook.url: \"{}\" is not an http(s) URL", webhook.url));
+            }
+            if webhook.max_attempts == 0 {
+                return Err(String::from("alerting.webhook.max_attempts: must be at least 1"));
+            }
+            EventFilter::new(&webhook.types, &webhook.paths)
+                .map_err(|e| format!("alerting.webhook: {}", e))?;
+        }
+
+        if let Some(syslog) = &self.alerting.syslog {
+            if facility_code(&syslog.facility).is_none() {
+                return Err(format!("alerting.syslog.facility: unknown facility \"{}\"", syslog.facility));
+            }
+            match syslog.transport {
+                SyslogTransport::Unix if !Path::new(&syslog.address).is_absolute() => {
+                    return Err(format!("alerting.syslog.address: {} is not an absolute socket path", syslog.address));
+                }
+                SyslogTransport::Udp | SyslogTransport::Tcp if syslog.address.to_socket_addrs().is_err() => {
+                    return Err(format!("alerting.syslog.address: \"{}\" is not a host:port address", syslog.address));
+                }
+                _ => ()
+            }
+            EventFilter::new(&syslog.types, &syslog.paths)
+                .map_err(|e| format!("alerting.syslog: {}", e))?;
+        }
+
+        Ok(())
+    }
+
+    // Written through a temporary file so that a crash never leaves a
+    // truncated configuration behind. The file keeps its permissions, and a
+    // new one is only readable by its owner, since it may hold the webhook
+    // secret.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let content = toml::to_string_pretty(self)?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mode = match fs::metadata(path) {
+            Ok(metadata) => metadata.permissions().mode() & 0o7777,
+            Err(_) => 0o600
+        };
+
+        let tmp_path = path.with_extension("toml.tmp");
+        let _ = fs::remove_file(&tmp_path);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn save_keeps_the_permissions_of_the_file() {
+        let path = testing::directory("config-mode").join("fim.toml");
+        fs::write(&path, "# secret inside\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        Config::default().save(&path).unwrap();
+
+        assert_eq!(mode(&path), 0o640);
+        assert!(Config::load(&path).is_ok());
+    }
+
+    #[test]
+    fn a_new_file_is_only_readable_by_its_owner() {
+        let path = testing::directory("config-new").join("etc/fim.toml");
+
+        Config::default().save(&path).unwrap();
+
+        assert_eq!(mode(&path), 0o600);
+        assert!(!path.with_extension("toml.tmp").exists());
+    }
+
+    #[test]
+    fn roots_cannot_hold_what_the_monitor_writes() {
+        let dir = testing::directory("config-own-files");
+        let path = dir.join("etc/fim.toml");
+        fs::create_dir_all(dir.join("etc")).unwrap();
+        fs::create_dir_all(dir.join("data/db")).unwrap();
+        fs::create_dir_all(dir.join("log")).unwrap();
+        fs::create_dir_all(dir.join("srv")).unwrap();
+
+        let mut config = Config::default();
+        config.storage.database = dir.join("data/db/../db/fim.db");
+        config.storage.event_log = Some(toml::from_str(&format!("path = {:?}", dir.join("log/events.jsonl"))).unwrap());
+        config.roots = vec![RootConfig::new(dir.join("srv"))];
+        assert_eq!(config.validate(&path), Ok(()));
+
+        for (root, file) in [("data", "data/db/fim.db"), ("log", "log/events.jsonl"), ("etc", "etc/fim.toml"), ("", "etc/fim.toml")] {
+            config.roots = vec![RootConfig::new(dir.join(root))];
+            let error = config.validate(&path).unwrap_err();
+            assert!(error.contains(&format!("contains {}", dir.join(file).display())), "{}", error);
+        }
+    }
+}
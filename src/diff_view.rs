@@ -0,0 +1,291 @@
+use std::error::Error;
+use diffy::{Line, Patch};
+use similar::{ChangeTag, TextDiff};
+
+
+
+// Below this similarity a changed line is shown as replaced as a whole
+// rather than word by word.
+const MIN_WORD_RATIO: f32 = 0.5;
+
+// A piece of a line, marked when it is what changed inside a line that was
+// edited rather than added or removed.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub text: String,
+    pub changed: bool
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffLine {
+    // "context", "delete" or "insert", also the CSS class of the line.
+    pub kind: &'static str,
+    pub old_number: Option<usize>,
+    pub new_number: Option<usize>,
+    pub segments: Vec<Segment>
+}
+
+// A hunk as a single column, and as the old and new files side by side.
+pub struct DiffHunk {
+    pub header: String,
+    pub lines: Vec<DiffLine>,
+    pub rows: Vec<(Option<DiffLine>, Option<DiffLine>)>
+}
+
+fn text(line: &[u8]) -> String {
+    String::from_utf8_lossy(line).trim_end_matches(['\n', '\r']).to_string()
+}
+
+fn whole(text: String) -> Vec<Segment> {
+    vec![Segment { text, changed: false }]
+}
+
+fn push(segments: &mut Vec<Segment>, text: &str, changed: bool) {
+    match segments.last_mut() {
+        Some(last) if last.changed == changed => last.text.push_str(text),
+        _ => segments.push(Segment { text: text.to_string(), changed })
+    }
+}
+
+// The words that differ between a removed line and the line added in its
+// place.
+fn word_segments(old: &str, new: &str) -> (Vec<Segment>, Vec<Segment>) {
+    let diff = TextDiff::from_words(old, new);
+    if diff.ratio() < MIN_WORD_RATIO {
+        return (whole(old.to_string()), whole(new.to_string()));
+    }
+
+    let mut old_segments = Vec::new();
+    let mut new_segments = Vec::new();
+    for change in diff.iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Equal => {
+                push(&mut old_segments, change.value(), false);
+                push(&mut new_segments, change.value(), false);
+            }
+            ChangeTag::Delete => push(&mut old_segments, change.value(), true),
+            ChangeTag::Insert => push(&mut new_segments, change.value(), true)
+        }
+    }
+
+    (old_segments, new_segments)
+}
+
+// Pairs the lines removed with the lines added after them, the first with
+// the first, and so on.
+fn flush(
+    deleted: &mut Vec<DiffLine>,
+    inserted: &mut Vec<DiffLine>,
+    lines: &mut Vec<DiffLine>,
+    rows: &mut Vec<(Option<DiffLine>, Option<DiffLine>)>
+) {
+
+    for (old, new) in deleted.iter_mut().zip(inserted.iter_mut()) {
+        let (old_segments, new_segments) = word_segments(&old.segments[0].text, &new.segments[0].text);
+        old.segments = old_segments;
+        new.segments = new_segments;
+    }
+
+    for index in 0..deleted.len().max(inserted.len()) {
+        rows.push((deleted.get(index).cloned(), inserted.get(index).cloned()));
+    }
+    lines.append(deleted);
+    lines.append(inserted);
+}
+
+pub fn hunks(
+    patch: &[u8]
+) -> Result<Vec<DiffHunk>, Box<dyn Error>> {
+
+    let patch = Patch::from_bytes(patch).map_err(|e| e.to_string())?;
+
+    let mut hunks = Vec::new();
+    for hunk in patch.hunks() {
+        let (old_range, new_range) = (hunk.old_range(), hunk.new_range());
+        let mut old_number = old_range.start();
+        let mut new_number = new_range.start();
+
+        let mut lines = Vec::new();
+        let mut rows = Vec::new();
+        let mut deleted = Vec::new();
+        let mut inserted = Vec::new();
+        for line in hunk.lines() {
+            match line {
+                Line::Context(content) => {
+                    flush(&mut deleted, &mut inserted, &mut lines, &mut rows);
+                    let line = DiffLine {
+                        kind: "context",
+                        old_number: Some(old_number),
+                        new_number: Some(new_number),
+                        segments: whole(text(content))
+                    };
+                    rows.push((Some(line.clone()), Some(line.clone())));
+                    lines.push(line);
+                    old_number += 1;
+                    new_number += 1;
+                }
+                Line::Delete(content) => {
+                    // A removal after additions starts a new group.
+                    if !inserted.is_empty() {
+                        flush(&mut deleted, &mut inserted, &mut lines, &mut rows);
+                    }
+                    deleted.push(DiffLine {
+                        kind: "delete",
+                        old_number: Some(old_number),
+                        new_number: None,
+                        segments: whole(text(content))
+                    });
+                    old_number += 1;
+                }
+                Line::Insert(content) => {
+                    inserted.push(DiffLine {
+                        kind: "insert",
+                        old_number: None,
+                        new_number: Some(new_number),
+                        segments: whole(text(content))
+                    });
+                    new_number += 1;
+                }
+            }
+        }
+        flush(&mut deleted, &mut inserted, &mut lines, &mut rows);
+
+        hunks.push(DiffHunk {
+            header: format!("@@ -{},{} +{},{} @@", old_range.start(), old_range.len(), new_range.start(), new_range.len()),
+            lines,
+            rows
+        });
+    }
+
+    Ok(hunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diffy::create_patch;
+
+    fn hunks_of(old: &str, new: &str) -> Vec<DiffHunk> {
+        hunks(&create_patch(old, new).to_bytes()).unwrap()
+    }
+
+    // Kind, old number, new number and text of each line.
+    fn numbered(hunk: &DiffHunk) -> Vec<(&'static str, Option<usize>, Option<usize>, String)> {
+        hunk.lines.iter()
+            .map(|line| (line.kind, line.old_number, line.new_number, line.segments.iter().map(|segment| segment.text.as_str()).collect()))
+            .collect()
+    }
+
+    fn texts(row: &(Option<DiffLine>, Option<DiffLine>)) -> (Option<String>, Option<String>) {
+        let text = |line: &Option<DiffLine>| line.as_ref().map(|line| line.segments.iter().map(|segment| segment.text.as_str()).collect());
+        (text(&row.0), text(&row.1))
+    }
+
+    fn segments(segments: &[Segment]) -> Vec<(&str, bool)> {
+        segments.iter().map(|segment| (segment.text.as_str(), segment.changed)).collect()
+    }
+
+    #[test]
+    fn lines_are_numbered_in_every_hunk() {
+        let old: String = (1..=20).map(|n| format!("line {}\n", n)).collect();
+        let new = old.replace("line 3\n", "line three\nline 3b\n").replace("line 17\n", "line 17\nline 17b\n");
+
+        let hunks = hunks_of(&old, &new);
+
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(hunks[0].header, "@@ -1,6 +1,7 @@");
+        assert_eq!(numbered(&hunks[0])[1..6], [
+            ("context", Some(2), Some(2), String::from("line 2")),
+            ("delete", Some(3), None, String::from("line 3")),
+            ("insert", None, Some(3), String::from("line three")),
+            ("insert", None, Some(4), String::from("line 3b")),
+            ("context", Some(4), Some(5), String::from("line 4"))
+        ]);
+        // The line added by the first hunk shifts the new numbers of the
+        // second.
+        assert_eq!(hunks[1].header, "@@ -15,6 +16,7 @@");
+        let second = numbered(&hunks[1]);
+        assert_eq!(second[0], ("context", Some(15), Some(16), String::from("line 15")));
+        assert_eq!(second[3], ("insert", None, Some(19), String::from("line 17b")));
+        assert_eq!(second[6], ("context", Some(20), Some(22), String::from("line 20")));
+        assert_eq!(second.len(), 7);
+    }
+
+    #[test]
+    fn removed_and_added_lines_are_paired_in_order() {
+        let hunks = hunks_of("keep\none two\nthree four\nfive six\nend\n", "keep\none 2\nend\n");
+
+        let rows: Vec<_> = hunks[0].rows.iter().map(texts).collect();
+        assert_eq!(rows, [
+            (Some(String::from("keep")), Some(String::from("keep"))),
+            (Some(String::from("one two")), Some(String::from("one 2"))),
+            (Some(String::from("three four")), None),
+            (Some(String::from("five six")), None),
+            (Some(String::from("end")), Some(String::from("end")))
+        ]);
+        // Only the paired lines are compared word by word.
+        assert_eq!(segments(&hunks[0].rows[1].0.as_ref().unwrap().segments), [("one ", false), ("two", true)]);
+        assert_eq!(segments(&hunks[0].rows[2].0.as_ref().unwrap().segments), [("three four", false)]);
+
+        let hunks = hunks_of("a\n", "b\nc\nd\n");
+        let rows: Vec<_> = hunks[0].rows.iter().map(texts).collect();
+        assert_eq!(rows, [
+            (Some(String::from("a")), Some(String::from("b"))),
+            (None, Some(String::from("c"))),
+            (None, Some(String::from("d")))
+        ]);
+        let kinds: Vec<_> = hunks[0].lines.iter().map(|line| line.kind).collect();
+        assert_eq!(kinds, ["delete", "insert", "insert", "insert"]);
+    }
+
+    #[test]
+    fn a_removal_after_additions_starts_a_new_pair() {
+        let patch = b"--- a\n+++ b\n@@ -1,2 +1,2 @@\n-a\n+b\n-c\n+d\n";
+
+        let hunks = hunks(patch).unwrap();
+
+        let rows: Vec<_> = hunks[0].rows.iter().map(texts).collect();
+        assert_eq!(rows, [
+            (Some(String::from("a")), Some(String::from("b"))),
+            (Some(String::from("c")), Some(String::from("d")))
+        ]);
+    }
+
+    #[test]
+    fn lines_too_different_are_highlighted_whole() {
+        let (old, new) = word_segments("let total = price * count;", "let total = price * quantity;");
+        assert_eq!(segments(&old), [("let total = price * ", false), ("count;", true)]);
+        assert_eq!(segments(&new), [("let total = price * ", false), ("quantity;", true)]);
+
+        let (old, new) = word_segments("alpha beta gamma", "one two three");
+        assert_eq!(segments(&old), [("alpha beta gamma", false)]);
+        assert_eq!(segments(&new), [("one two three", false)]);
+        assert!(TextDiff::from_words("alpha beta gamma", "one two three").ratio() < MIN_WORD_RATIO);
+    }
+
+    #[test]
+    fn missing_final_newlines_are_not_shown_as_text() {
+        let patch = create_patch("a\nb", "a\nc").to_bytes();
+        assert!(String::from_utf8_lossy(&patch).contains("\\ No newline at end of file"));
+
+        let hunks = hunks(&patch).unwrap();
+
+        assert_eq!(numbered(&hunks[0]), [
+            ("context", Some(1), Some(1), String::from("a")),
+            ("delete", Some(2), None, String::from("b")),
+            ("insert", None, Some(2), String::from("c"))
+        ]);
+
+        // Only the newline went away: the line is the same on both sides.
+        let hunks = hunks_of("a\nb\n", "a\nb");
+        let rows: Vec<_> = hunks[0].rows.iter().map(texts).collect();
+        assert_eq!(rows[1], (Some(String::from("b")), Some(String::from("b"))));
+        assert!(hunks[0].lines.iter().all(|line| line.segments.iter().all(|segment| !segment.changed)));
+    }
+
+    #[test]
+    fn a_damaged_patch_is_an_error() {
+        assert!(hunks(b"--- a\n+++ b\n@@ -1,2 +1,2 @@\n-a\n").is_err());
+        assert!(hunks(b"").unwrap().is_empty());
+    }
+}
@@ -1,15 +1,16 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use inotify::{Inotify, WatchDescriptor};
 use std::error::Error;
 use std::collections::HashMap;
 
 use crate::watcher::watch_directory_recursive;
+use crate::filter::Filters;
 
 
 
 pub fn dir_moved_from(
     inotify: &Inotify,
-    complete_path: &PathBuf,
+    complete_path: &Path,
     watched_dirs: &mut HashMap<WatchDescriptor, PathBuf>,
 ) -> Result<(), Box<dyn Error>> {
 
@@ -31,25 +32,45 @@ pub fn dir_moved_from(
 
 pub fn dir_moved_to(
     inotify: &Inotify,
-    complete_path: &PathBuf,
+    complete_path: &Path,
     watched_dirs: &mut HashMap<WatchDescriptor, PathBuf>,
+    filters: &Filters
 ) -> Result<(), Box<dyn Error>> {
 
-    watch_directory_recursive(&inotify, &complete_path, watched_dirs)?;
+    watch_directory_recursive(inotify, complete_path, watched_dirs, filters)?;
 
     Ok(())
 }
 
-pub fn dir_delete(
-    complete_path: &PathBuf,
+// Watches follow the inode, so a rename inside the watched tree only has to
+// update the paths we resolve event names against.
+pub fn dir_rename(
+    from: &Path,
+    to: &Path,
     watched_dirs: &mut HashMap<WatchDescriptor, PathBuf>,
 ) -> Result<(), Box<dyn Error>> {
 
-    let to_remove: WatchDescriptor;
+    for value in watched_dirs.values_mut() {
+        if let Ok(relative) = value.strip_prefix(from) {
+            *value = if relative.as_os_str().is_empty() {
+                to.to_path_buf()
+            } else {
+                to.join(relative)
+            };
+        }
+    }
+
+    Ok(())
+}
+
+pub fn dir_delete(
+    complete_path: &Path,
+    watched_dirs: &mut HashMap<WatchDescriptor, PathBuf>,
+) -> Result<(), Box<dyn Error>> {
 
     for (key, value) in watched_dirs.iter() {
-        if value.to_path_buf() == complete_path.clone() {
-            to_remove = key.clone();
+        if value == complete_path {
+            let to_remove = key.clone();
             watched_dirs.remove(&to_remove);
             break;
         }
@@ -60,11 +81,12 @@ pub fn dir_delete(
 
 pub fn dir_create(
     inotify: &Inotify,
-    complete_path: &PathBuf,
+    complete_path: &Path,
     watched_dirs: &mut HashMap<WatchDescriptor, PathBuf>,
+    filters: &Filters
 ) -> Result<(), Box<dyn Error>> {
 
-    watch_directory_recursive(inotify, complete_path, watched_dirs)?;
+    watch_directory_recursive(inotify, complete_path, watched_dirs, filters)?;
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,34 +1,37 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::error::Error;
 use std::fs::{self, File};
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, Connection, OptionalExtension, Result};
 use std::io::prelude::*;
-use diffy::create_patch_bytes;
+use diffy::{apply_bytes, create_patch_bytes, Patch};
 
+use crate::metadata::FileMeta;
+use crate::hash::Digests;
+use crate::blob;
+use crate::filter::Filters;
+use crate::session;
+use crate::sink::{self, EventRecord};
 
 
-#[derive(Debug)]
-struct PatchLastCopy {
-    last_copy: Vec<u8>
-}
 
-fn get_diff(
-    conn: &Connection,
-    path: &PathBuf
-) -> Result<Vec<u8>, Box<dyn Error>> {
+#[derive(Debug, Default)]
+struct ContentChange {
+    diff: Vec<u8>,
+    content: Vec<u8>,
+    old: Option<Digests>,
+    new: Option<Digests>,
+    // Who asked for the change, when it was made by the monitor itself.
+    operator: Option<String>
+}
 
-    let mut stmt = conn.prepare("SELECT last_copy FROM path WHERE file_path = ?1")?;
-    let patch: PatchLastCopy = stmt.query_row(params![path.to_string_lossy()], |row| {
-        Ok(PatchLastCopy {
-            last_copy: row.get(0)?
-        })
-    })?;
-    let buffer_save = patch.last_copy;
+fn read_file(
+    path: &Path
+) -> Vec<u8> {
 
-    let buffer_new = match File::open(&path) {
+    match File::open(path) {
         Ok(mut file) => {
             let mut buffer = Vec::new();
-            if let Err(_) = file.read_to_end(&mut buffer) {
+            if file.read_to_end(&mut buffer).is_err() {
                 Vec::new()
             } else {
                 buffer
@@ -37,157 +40,402 @@ fn get_diff(
         Err(_) => {
             Vec::new()
         }
+    }
+}
+
+fn get_digests(
+    conn: &Connection,
+    path: &Path
+) -> Result<Digests, Box<dyn Error>> {
+
+    let mut stmt = conn.prepare("SELECT sha256, blake3 FROM path WHERE file_path = ?1")?;
+    let digests = stmt.query_row(params![path.to_string_lossy()], |row| {
+        Ok(Digests {
+            sha256: row.get(0)?,
+            blake3: row.get(1)?
+        })
+    })?;
+
+    Ok(digests)
+}
+
+fn get_last_copy(
+    conn: &Connection,
+    path: &Path
+) -> Result<Vec<u8>, Box<dyn Error>> {
+
+    let digests = get_digests(conn, path)?;
+
+    blob::get(conn, &digests.sha256)
+}
+
+// Digests are compared first so that the stored copy is only loaded when
+// the file actually changed.
+fn get_diff(
+    conn: &Connection,
+    path: &Path
+) -> Result<ContentChange, Box<dyn Error>> {
+
+    let buffer_new = read_file(path);
+    let new = Digests::of(&buffer_new);
+    let old = get_digests(conn, path)?;
+
+    let diff = if old.sha256 != new.sha256 {
+        let buffer_save = get_last_copy(conn, path)?;
+        create_patch_bytes(&buffer_save, &buffer_new).to_bytes()
+    } else {
+        Vec::new()
     };
 
-    if buffer_save != buffer_new {
-        let diff = create_patch_bytes(&buffer_save, &buffer_new);
+    Ok(ContentChange {
+        diff,
+        content: buffer_new,
+        old: Some(old),
+        new: Some(new),
+        operator: None
+    })
+}
+
+fn is_modified(
+    conn: &Connection,
+    path: &Path
+) -> Result<bool, Box<dyn Error>> {
 
-        return Ok(diff.to_bytes());
-    }
+    let new = Digests::of(&read_file(path));
+    let old = get_digests(conn, path)?;
 
-    Ok(Vec::new())
+    Ok(old.sha256 != new.sha256)
 }
 
 fn is_path_present(
     conn: &Connection,
-    path: &PathBuf
+    path: &Path
 ) -> Result<bool> {
     let mut stmt = conn.prepare("SELECT EXISTS(SELECT 1 FROM path WHERE file_path = ?1)")?;
     let exists: bool = stmt.query_row(params![path.to_string_lossy()], |row| row.get(0))?;
     Ok(exists)
 }
 
-fn update_copy(
+fn get_meta(
+    conn: &Connection,
+    path: &Path
+) -> Result<Option<FileMeta>> {
+    let mut stmt = conn.prepare("SELECT mode, uid, gid, size, mtime, ctime, inode, nlink FROM path WHERE file_path = ?1 AND mode IS NOT NULL")?;
+    stmt.query_row(params![path.to_string_lossy()], |row| {
+        Ok(FileMeta {
+            mode: row.get(0)?,
+            uid: row.get(1)?,
+            gid: row.get(2)?,
+            size: row.get::<_, i64>(3)? as u64,
+            mtime: row.get(4)?,
+            ctime: row.get(5)?,
+            inode: row.get::<_, i64>(6)? as u64,
+            nlink: row.get::<_, i64>(7)? as u64
+        })
+    }).optional()
+}
+
+fn update_meta(
     conn: &Connection,
-    path: &PathBuf
+    path: &Path
 ) -> Result<(), Box<dyn Error>> {
 
-    let copy = match File::open(&path) {
-        Ok(mut file) => {
-            let mut buffer = Vec::new();
-            if let Err(_) = file.read_to_end(&mut buffer) {
-                Vec::new()
-            } else {
-                buffer
-            }
-        }
-        Err(_) => {
-            Vec::new()
-        }
+    let meta = match FileMeta::from_path(path) {
+        Some(meta) => meta,
+        None => return Ok(())
     };
 
     conn.execute(
-        "UPDATE path SET last_copy = ?1 WHERE file_path = ?2",
-        params![&copy, path.to_string_lossy()],
+        "UPDATE path SET mode = ?1, uid = ?2, gid = ?3, size = ?4, mtime = ?5, ctime = ?6, inode = ?7, nlink = ?8 WHERE file_path = ?9",
+        params![
+            meta.mode,
+            meta.uid,
+            meta.gid,
+            meta.size as i64,
+            meta.mtime,
+            meta.ctime,
+            meta.inode as i64,
+            meta.nlink as i64,
+            path.to_string_lossy()
+        ],
     )?;
 
     Ok(())
 }
 
-fn create_file_db(
+fn update_copy(
     conn: &Connection,
-    path: &PathBuf
+    path: &Path,
+    change: &ContentChange
 ) -> Result<(), Box<dyn Error>> {
 
-    let buffer_file_create = match File::open(&path) {
-        Ok(mut file) => {
-            let mut buffer = Vec::new();
-            if let Err(_) = file.read_to_end(&mut buffer) {
-                Vec::new()
-            } else {
-                buffer
-            }
-        }
-        Err(_) => {
-            Vec::new()
-        }
+    let digests = match &change.new {
+        Some(digests) => digests,
+        None => return Ok(())
     };
+    let old = get_digests(conn, path)?;
 
-    conn.execute(
-        "INSERT INTO path (file_path, last_copy) VALUES (?1, ?2)",
-        params![path.to_string_lossy(), &buffer_file_create],
+    if old.sha256 != digests.sha256 {
+        blob::put(conn, &change.content, digests)?;
+        conn.execute(
+            "UPDATE path SET sha256 = ?1, blake3 = ?2 WHERE file_path = ?3",
+            params![digests.sha256, digests.blake3, path.to_string_lossy()],
+        )?;
+        blob::release(conn, &old.sha256)?;
+    }
+
+    update_meta(conn, path)?;
+
+    Ok(())
+}
+
+// Databases written before path(file_path) was unique could hold a file
+// twice; the events of the copies move to the first row, and the reference
+// each copy held on its blob is given back.
+pub fn dedupe_paths(
+    conn: &Connection
+) -> Result<(), Box<dyn Error>> {
+
+    let mut stmt = conn.prepare(
+        "SELECT path.id, path.sha256, first.id FROM path INNER JOIN
+            (SELECT file_path, min(id) AS id FROM path GROUP BY file_path HAVING count(*) > 1) AS first
+            ON path.file_path = first.file_path
+        WHERE path.id > first.id"
     )?;
+    let copies = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, i64>(2)?))
+    })?.collect::<Result<Vec<_>>>()?;
+
+    for (id, sha256, first) in copies {
+        conn.execute("UPDATE event SET path_id = ?1 WHERE path_id = ?2", params![first, id])?;
+        conn.execute("UPDATE event SET from_path_id = ?1 WHERE from_path_id = ?2", params![first, id])?;
+        if let Some(sha256) = sha256 {
+            blob::release(conn, &sha256)?;
+        }
+        conn.execute("DELETE FROM path WHERE id = ?1", params![id])?;
+    }
 
     Ok(())
 }
 
-fn delete_file(
+fn create_file_db(
     conn: &Connection,
-    path: &PathBuf
+    path: &Path
 ) -> Result<(), Box<dyn Error>> {
 
+    let buffer_file_create = read_file(path);
+    let digests = Digests::of(&buffer_file_create);
+
+    blob::put(conn, &buffer_file_create, &digests)?;
     conn.execute(
-        "INSERT INTO event (type_event, diff, path_id) VALUES (?1, ?2, (SELECT id FROM path WHERE file_path = ?3))",
-        params!["DELETE", Vec::new(), path.to_string_lossy()],
+        "INSERT INTO path (file_path, sha256, blake3) VALUES (?1, ?2, ?3)",
+        params![path.to_string_lossy(), digests.sha256, digests.blake3],
     )?;
 
+    update_meta(conn, path)?;
+
     Ok(())
 }
 
-fn moved_from_file(
+// Every event keeps its own reference on the blobs of the versions before
+// and after it, so any past version can be read back from its event.
+fn insert_event(
     conn: &Connection,
-    path: &PathBuf
-) -> Result<(), Box<dyn Error>> {
+    type_event: &str,
+    path: &Path,
+    from_path: Option<&Path>,
+    change: &ContentChange
+) -> Result<i64, Box<dyn Error>> {
+
+    if let Some(old) = &change.old {
+        blob::acquire(conn, &old.sha256)?;
+    }
+    if let Some(new) = &change.new {
+        blob::put(conn, &change.content, new)?;
+    }
 
-    println!("toto");
+    // Mode and ownership of the file after the event, or its last known ones
+    // when it is gone, so that a restore can put them back.
+    let meta = match FileMeta::from_path(path) {
+        Some(meta) => Some(meta),
+        None => get_meta(conn, path)?
+    };
 
     conn.execute(
-        "INSERT INTO event (type_event, diff, path_id) VALUES (?1, ?2, (SELECT id FROM path WHERE file_path = ?3))",
-        params!["MOVED_FROM", Vec::new(), path.to_string_lossy()],
+        "INSERT INTO event (type_event, diff, path_id, from_path_id, old_sha256, new_sha256, old_blake3, new_blake3, mode, uid, gid, operator)
+        VALUES (?1, ?2, (SELECT id FROM path WHERE file_path = ?3), (SELECT id FROM path WHERE file_path = ?4), ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        params![
+            type_event,
+            &change.diff,
+            path.to_string_lossy(),
+            from_path.map(|p| p.to_string_lossy()),
+            change.old.as_ref().map(|d| &d.sha256),
+            change.new.as_ref().map(|d| &d.sha256),
+            change.old.as_ref().and_then(|d| d.blake3.as_ref()),
+            change.new.as_ref().and_then(|d| d.blake3.as_ref()),
+            meta.as_ref().map(|m| m.mode),
+            meta.as_ref().map(|m| m.uid),
+            meta.as_ref().map(|m| m.gid),
+            change.operator
+        ],
     )?;
+    let event_id = conn.last_insert_rowid();
+
+    sink::emit(conn, &EventRecord {
+        id: event_id,
+        type_event: String::from(type_event),
+        path: path.to_string_lossy().into_owned(),
+        from_path: from_path.map(|p| p.to_string_lossy().into_owned()),
+        old_sha256: change.old.as_ref().map(|d| d.sha256.clone()),
+        new_sha256: change.new.as_ref().map(|d| d.sha256.clone()),
+        old_blake3: change.old.as_ref().and_then(|d| d.blake3.clone()),
+        new_blake3: change.new.as_ref().and_then(|d| d.blake3.clone()),
+        size: meta.as_ref().map(|m| m.size),
+        mode: meta.as_ref().map(|m| format!("{:04o}", m.mode & 0o7777)),
+        uid: meta.as_ref().map(|m| m.uid),
+        gid: meta.as_ref().map(|m| m.gid),
+        operator: change.operator.clone(),
+        timestamp: sink::now(),
+        host: sink::hostname()
+    })?;
+
+    Ok(event_id)
+}
+
+fn delete_file(
+    conn: &Connection,
+    path: &Path,
+    type_event: &str
+) -> Result<(), Box<dyn Error>> {
+
+    let change = ContentChange {
+        old: Some(get_digests(conn, path)?),
+        ..Default::default()
+    };
+    insert_event(conn, type_event, path, None, &change)?;
+
+    Ok(())
+}
+
+fn moved_out_file(
+    conn: &Connection,
+    path: &Path
+) -> Result<(), Box<dyn Error>> {
+
+    let change = ContentChange {
+        old: Some(get_digests(conn, path)?),
+        ..Default::default()
+    };
+    insert_event(conn, "MOVED_OUT", path, None, &change)?;
 
     Ok(())
 }
 
-fn moved_to_file(
+fn moved_in_file(
     conn: &Connection,
-    path: &PathBuf
+    path: &Path
 ) -> Result<(), Box<dyn Error>> {
 
-    let diff = get_diff(&conn, &path)?;
-    for &byte in &diff {
+    let mut change = get_diff(conn, path)?;
+    for &byte in &change.diff {
         print!("{}", byte as char);
     }
+    if change.diff.is_empty() {
+        change.old = None;
+    }
 
-    conn.execute(
-        "INSERT INTO event (type_event, diff, path_id) VALUES (?1, ?2, (SELECT id FROM path WHERE file_path = ?3))",
-        params!["MOVED_TO", &diff, path.to_string_lossy()],
-    )?;
+    insert_event(conn, "MOVED_IN", path, None, &change)?;
 
-    update_copy(&conn, &path)?;
+    update_copy(conn, path, &change)?;
 
     Ok(())
 }
-    
+
 fn modify_file(
     conn: &Connection,
-    path: &PathBuf
+    path: &Path,
+    type_event: &str
 ) -> Result<(), Box<dyn Error>> {
 
-    let diff = get_diff(&conn, &path)?;
-    for &byte in &diff {
+    let old_meta = get_meta(conn, path)?;
+    let change = get_diff(conn, path)?;
+    for &byte in &change.diff {
         print!("{}", byte as char);
     }
 
-    conn.execute(
-        "INSERT INTO event (type_event, diff, path_id) VALUES (?1, ?2, (SELECT id FROM path WHERE file_path = ?3))",
-        params!["MODIFY", &diff, path.to_string_lossy()],
-    )?;
+    insert_event(conn, type_event, path, None, &change)?;
 
-    update_copy(&conn, &path)?;
+    // The new content also becomes the metadata baseline: a mode or an
+    // owner changed along with it would otherwise never be reported.
+    if let (Some(old_meta), Some(new_meta)) = (old_meta, FileMeta::from_path(path)) {
+        let changes = old_meta.ownership_changes(&new_meta);
+        if !changes.is_empty() {
+            let payload = changes.join("\n");
+            println!("{}", payload);
+            insert_event(conn, "ATTRIB", path, None, &ContentChange {
+                diff: payload.into_bytes(),
+                ..Default::default()
+            })?;
+        }
+    }
+
+    update_copy(conn, path, &change)?;
 
     Ok(())
 }
 
 fn maybe_modify_file(
     conn: &Connection,
-    path: &PathBuf
+    path: &Path,
+    type_event: &str
 ) -> Result<(), Box<dyn Error>> {
 
-    let diff = get_diff(&conn, &path)?;
+    if is_modified(conn, path)? {
+        modify_file(conn, path, type_event)?;
+    }
+
+    Ok(())
+}
 
-    if !diff.is_empty() {
-        modify_file(&conn, &path)?;
+fn attrib_file(
+    conn: &Connection,
+    path: &Path
+) -> Result<(), Box<dyn Error>> {
+
+    let new_meta = match FileMeta::from_path(path) {
+        Some(meta) => meta,
+        None => return Ok(())
+    };
+
+    let changes = match get_meta(conn, path)? {
+        Some(old_meta) => old_meta.changes(&new_meta),
+        None => new_meta.describe()
+    };
+
+    if !changes.is_empty() {
+        let payload = changes.join("\n");
+        println!("{}", payload);
+        let change = ContentChange {
+            diff: payload.into_bytes(),
+            ..Default::default()
+        };
+        insert_event(conn, "ATTRIB", path, None, &change)?;
+    }
+
+    update_meta(conn, path)?;
+
+    Ok(())
+}
+
+fn maybe_attrib_file(
+    conn: &Connection,
+    path: &Path
+) -> Result<(), Box<dyn Error>> {
+
+    if get_meta(conn, path)?.is_some() {
+        attrib_file(conn, path)?;
+    } else {
+        update_meta(conn, path)?;
     }
 
     Ok(())
@@ -195,50 +443,251 @@ fn maybe_modify_file(
 
 fn create_file(
     conn: &Connection,
-    path: &PathBuf
+    path: &Path,
+    type_event: &str
 ) -> Result<(), Box<dyn Error>> {
 
-    let diff = get_diff(&conn, &path)?;
-    for &byte in &diff {
+    let mut change = get_diff(conn, path)?;
+    for &byte in &change.diff {
         print!("{}", byte as char);
     }
+    if change.diff.is_empty() {
+        change.old = None;
+    }
 
-    conn.execute(
-        "INSERT INTO event (type_event, diff, path_id) VALUES (?1, ?2, (SELECT id FROM path WHERE file_path = ?3))",
-        params!["CREATE", &diff, path.to_string_lossy()],
-    )?;
+    insert_event(conn, type_event, path, None, &change)?;
 
-    update_copy(&conn, &path)?;
+    update_copy(conn, path, &change)?;
 
     Ok(())
 }
 
 pub fn check_file(
     conn: &Connection,
-    path: &PathBuf,
+    path: &Path,
     event: &str
 ) -> Result<(), Box<dyn Error>> {
 
-    if !is_path_present(&conn, &path)? {
-        create_file_db(&conn, &path)?;
+    // Every file of a scan goes through here, which keeps the session alive
+    // however long the scan takes.
+    session::beat(conn)?;
+
+    if !is_path_present(conn, path)? {
+        create_file_db(conn, path)?;
     }
 
     match event {
-        "DELETE" => delete_file(&conn, &path)?,
-        "MOVED_FROM" => moved_from_file(&conn, &path)?,
-        "MOVED_TO" => moved_to_file(&conn, &path)?,
-        "MAYBE_MODIFY" => maybe_modify_file(&conn, &path)?,
-        "MODIFY" => modify_file(&conn, &path)?,
-        "CREATE" => create_file(&conn, &path)?,
+        "DELETE" => delete_file(conn, path, "DELETE")?,
+        "OFFLINE_DELETE" => delete_file(conn, path, "OFFLINE_DELETE")?,
+        "MOVED_OUT" => moved_out_file(conn, path)?,
+        "MOVED_IN" => moved_in_file(conn, path)?,
+        "MAYBE_MODIFY" => maybe_modify_file(conn, path, "MODIFY")?,
+        "MAYBE_OFFLINE_MODIFY" => maybe_modify_file(conn, path, "OFFLINE_MODIFY")?,
+        "MODIFY" => modify_file(conn, path, "MODIFY")?,
+        "CREATE" => create_file(conn, path, "CREATE")?,
+        "OFFLINE_CREATE" => create_file(conn, path, "OFFLINE_CREATE")?,
+        "ATTRIB" => attrib_file(conn, path)?,
+        "MAYBE_ATTRIB" => maybe_attrib_file(conn, path)?,
         _ => ()
     }
 
     Ok(())
 }
 
+// The root only gets a path row to hang the event on, without content or
+// metadata: it is a directory, not a version that could be compared or
+// restored.
+pub fn record_overflow(
+    conn: &Connection,
+    root: &Path
+) -> Result<(), Box<dyn Error>> {
+
+    conn.execute("INSERT OR IGNORE INTO path (file_path) VALUES (?1)", params![root.to_string_lossy()])?;
+    insert_event(conn, "OVERFLOW", root, None, &ContentChange::default())?;
+
+    Ok(())
+}
+
+// Roots used to be recorded as files holding the empty content before
+// their OVERFLOW events; they lose that content and its blob reference.
+pub fn strip_overflow_paths(
+    conn: &Connection
+) -> Result<(), Box<dyn Error>> {
+
+    let mut stmt = conn.prepare(
+        "SELECT id, sha256 FROM path WHERE sha256 IS NOT NULL
+            AND id IN (SELECT path_id FROM event WHERE type_event = 'OVERFLOW')
+            AND id NOT IN (SELECT path_id FROM event WHERE type_event != 'OVERFLOW')
+            AND id NOT IN (SELECT from_path_id FROM event WHERE from_path_id IS NOT NULL)"
+    )?;
+    let roots = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<Result<Vec<_>>>()?;
+
+    for (id, sha256) in roots {
+        conn.execute(
+            "UPDATE path SET sha256 = NULL, blake3 = NULL, mode = NULL, uid = NULL, gid = NULL, size = NULL,
+                mtime = NULL, ctime = NULL, inode = NULL, nlink = NULL WHERE id = ?1",
+            params![id],
+        )?;
+        blob::release(conn, &sha256)?;
+    }
+
+    Ok(())
+}
+
+// The destination inherits the last copy of the source so that a plain
+// rename records an empty diff and a rename over new content records the
+// changes made to the file on its way.
+pub fn rename_file(
+    conn: &Connection,
+    from: &Path,
+    to: &Path
+) -> Result<(), Box<dyn Error>> {
+
+    session::beat(conn)?;
+
+    if !is_path_present(conn, from)? {
+        create_file_db(conn, from)?;
+    }
+
+    if !is_path_present(conn, to)? {
+        conn.execute(
+            "INSERT INTO path (file_path, sha256, blake3) SELECT ?1, sha256, blake3 FROM path WHERE file_path = ?2",
+            params![to.to_string_lossy(), from.to_string_lossy()],
+        )?;
+        blob::acquire(conn, &get_digests(conn, to)?.sha256)?;
+        update_meta(conn, to)?;
+    }
+
+    let change = get_diff(conn, to)?;
+    for &byte in &change.diff {
+        print!("{}", byte as char);
+    }
+
+    insert_event(conn, "RENAME", to, Some(from), &change)?;
+
+    update_copy(conn, to, &change)?;
+
+    Ok(())
+}
+
+pub fn record_restore(
+    conn: &Connection,
+    path: &Path,
+    operator: &str
+) -> Result<i64, Box<dyn Error>> {
+
+    if !is_path_present(conn, path)? {
+        create_file_db(conn, path)?;
+    }
+
+    let change = ContentChange {
+        operator: Some(String::from(operator)),
+        ..get_diff(conn, path)?
+    };
+    let event_id = insert_event(conn, "RESTORE", path, None, &change)?;
+
+    update_copy(conn, path, &change)?;
+
+    Ok(event_id)
+}
+
+fn files_under(
+    conn: &Connection,
+    dir: &Path
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+
+    let prefix = format!("{}/", dir.to_string_lossy());
+    let mut stmt = conn.prepare("SELECT file_path FROM path WHERE substr(file_path, 1, length(?1)) = ?1")?;
+    let rows = stmt.query_map(params![prefix], |row| row.get::<_, String>(0))?;
+
+    let mut files = Vec::new();
+    for row in rows {
+        files.push(PathBuf::from(row?));
+    }
+    Ok(files)
+}
+
+pub fn rename_dir(
+    conn: &Connection,
+    from: &Path,
+    to: &Path,
+    filters: &Filters
+) -> Result<(), Box<dyn Error>> {
+
+    for old_path in files_under(conn, from)? {
+        let new_path = match old_path.strip_prefix(from) {
+            Ok(relative) => to.join(relative),
+            Err(_) => continue
+        };
+
+        if !new_path.exists() {
+            continue;
+        }
+        if filters.is_recorded(&new_path, new_path.is_dir()) {
+            rename_file(conn, &old_path, &new_path)?;
+        } else {
+            check_file(conn, &old_path, "MOVED_OUT")?;
+        }
+    }
+
+    Ok(())
+}
+
+fn is_gone(
+    conn: &Connection,
+    path: &Path
+) -> Result<bool> {
+    let mut stmt = conn.prepare(
+        "SELECT CASE WHEN event.path_id = path.id THEN event.type_event ELSE 'RENAMED_AWAY' END
+        FROM event INNER JOIN path ON event.path_id = path.id OR event.from_path_id = path.id
+        WHERE path.file_path = ?1 ORDER BY event.id DESC LIMIT 1"
+    )?;
+    let last_event: Option<String> = stmt.query_row(params![path.to_string_lossy()], |row| row.get(0)).optional()?;
+    Ok(matches!(last_event.as_deref(), Some("DELETE") | Some("OFFLINE_DELETE") | Some("MOVED_OUT") | Some("RENAMED_AWAY")))
+}
+
+pub fn moved_out_dir(
+    conn: &Connection,
+    dir: &Path
+) -> Result<(), Box<dyn Error>> {
+
+    for path in files_under(conn, dir)? {
+        if !is_gone(conn, &path)? {
+            check_file(conn, &path, "MOVED_OUT")?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn moved_in_dir(
+    conn: &Connection,
+    dir: &Path,
+    filters: &Filters
+) -> Result<(), Box<dyn Error>> {
+
+    if dir.is_dir() {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path.is_file() {
+                if filters.is_recorded(&path, false) {
+                    check_file(conn, &path, "MOVED_IN")?;
+                }
+            } else if path.is_dir() && !filters.is_excluded(&path, true) {
+                moved_in_dir(conn, &path, filters)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
 pub fn check_rec(
     conn: &Connection,
-    dir: &PathBuf,
+    dir: &Path,
+    filters: &Filters
 ) -> Result<(), Box<dyn Error>> {
 
     if dir.is_dir() {
@@ -247,12 +696,573 @@ pub fn check_rec(
             let path = entry.path();
 
             if path.is_file() {
-                check_file(&conn, &path, "MAYBE_MODIFY")?;
-            } else if path.is_dir() {
-                check_rec(&conn, &path)?;
+                if filters.is_recorded(&path, false) {
+                    check_file(conn, &path, "MAYBE_MODIFY")?;
+                }
+            } else if path.is_dir() && !filters.is_excluded(&path, true) {
+                if filters.is_recorded(&path, true) {
+                    check_file(conn, &path, "MAYBE_ATTRIB")?;
+                }
+                check_rec(conn, &path, filters)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn reconcile_rec(
+    conn: &Connection,
+    dir: &Path,
+    filters: &Filters,
+    offline: bool
+) -> Result<(), Box<dyn Error>> {
+
+    if dir.is_dir() {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path.is_file() {
+                if !filters.is_recorded(&path, false) {
+                    continue;
+                }
+                if !is_path_present(conn, &path)? || is_gone(conn, &path)? {
+                    check_file(conn, &path, if offline { "OFFLINE_CREATE" } else { "CREATE" })?;
+                } else {
+                    check_file(conn, &path, if offline { "MAYBE_OFFLINE_MODIFY" } else { "MAYBE_MODIFY" })?;
+                    check_file(conn, &path, "MAYBE_ATTRIB")?;
+                }
+            } else if path.is_dir() && !filters.is_excluded(&path, true) {
+                if filters.is_recorded(&path, true) {
+                    check_file(conn, &path, "MAYBE_ATTRIB")?;
+                }
+                reconcile_rec(conn, &path, filters, offline)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Unlike check_rec, also records the files that appeared or disappeared
+// while events were not being delivered. With offline, the monitor was not
+// running at all and the events say so, since their date is only the date
+// they were found.
+pub fn reconcile(
+    conn: &Connection,
+    root: &Path,
+    filters: &Filters,
+    offline: bool
+) -> Result<(), Box<dyn Error>> {
+
+    reconcile_rec(conn, root, filters, offline)?;
+
+    for path in files_under(conn, root)? {
+        if !path.exists() && filters.is_recorded(&path, false) && !is_gone(conn, &path)? {
+            check_file(conn, &path, if offline { "OFFLINE_DELETE" } else { "DELETE" })?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn has_baseline(
+    conn: &Connection,
+    root: &Path
+) -> Result<bool, Box<dyn Error>> {
+
+    Ok(!files_under(conn, root)?.is_empty())
+}
+
+#[derive(Default)]
+pub struct Drift {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub changed: Vec<(PathBuf, Vec<String>)>,
+    pub metadata: Vec<(PathBuf, Vec<String>)>
+}
+
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty() && self.metadata.is_empty()
+    }
+}
+
+fn compare_path(
+    conn: &Connection,
+    path: &Path,
+    is_dir: bool,
+    drift: &mut Drift
+) -> Result<(), Box<dyn Error>> {
+
+    if !is_path_present(conn, path)? || is_gone(conn, path)? {
+        drift.added.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let meta_changes = match (get_meta(conn, path)?, FileMeta::from_path(path)) {
+        (Some(old), Some(new)) => old.changes(&new),
+        _ => Vec::new()
+    };
+
+    if !is_dir {
+        let old = get_digests(conn, path)?;
+        let new = Digests::of(&read_file(path));
+        if old.sha256 != new.sha256 {
+            let mut details = vec![format!("sha256: {} -> {}", old.sha256, new.sha256)];
+            details.extend(meta_changes);
+            drift.changed.push((path.to_path_buf(), details));
+            return Ok(());
+        }
+    }
+
+    if !meta_changes.is_empty() {
+        drift.metadata.push((path.to_path_buf(), meta_changes));
+    }
+
+    Ok(())
+}
+
+fn compare_rec(
+    conn: &Connection,
+    dir: &Path,
+    filters: &Filters,
+    drift: &mut Drift
+) -> Result<(), Box<dyn Error>> {
+
+    if dir.is_dir() {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path.is_file() {
+                if filters.is_recorded(&path, false) {
+                    compare_path(conn, &path, false, drift)?;
+                }
+            } else if path.is_dir() && !filters.is_excluded(&path, true) {
+                if filters.is_recorded(&path, true) {
+                    compare_path(conn, &path, true, drift)?;
+                }
+                compare_rec(conn, &path, filters, drift)?;
             }
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// The same walk as reconcile, but only reads the database: nothing is
+// recorded, the differences are collected in drift.
+pub fn compare(
+    conn: &Connection,
+    root: &Path,
+    filters: &Filters,
+    drift: &mut Drift
+) -> Result<(), Box<dyn Error>> {
+
+    compare_rec(conn, root, filters, drift)?;
+
+    for path in files_under(conn, root)? {
+        if !path.exists() && filters.is_recorded(&path, false) && !is_gone(conn, &path)? {
+            drift.removed.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+pub struct Version {
+    pub event_id: i64,
+    pub type_event: String,
+    pub date_event: String,
+    pub from_path: Option<String>,
+    pub old_sha256: Option<String>,
+    pub sha256: Option<String>,
+    pub has_diff: bool
+}
+
+pub fn history(
+    conn: &Connection,
+    path: &Path
+) -> Result<Vec<Version>, Box<dyn Error>> {
+
+    let mut stmt = conn.prepare(
+        "SELECT event.id, CASE WHEN event.path_id = path.id THEN event.type_event ELSE 'RENAMED_AWAY' END,
+            strftime('%Y-%m-%d %H:%M:%S', event.date_event), from_path.file_path,
+            event.old_sha256, CASE WHEN event.path_id = path.id THEN event.new_sha256 END, length(event.diff) > 0
+        FROM event INNER JOIN path ON event.path_id = path.id OR event.from_path_id = path.id
+        LEFT JOIN path AS from_path ON event.from_path_id = from_path.id
+        WHERE path.file_path = ?1 ORDER BY event.id DESC"
+    )?;
+    let rows = stmt.query_map(params![path.to_string_lossy()], |row| {
+        Ok(Version {
+            event_id: row.get(0)?,
+            type_event: row.get(1)?,
+            date_event: row.get(2)?,
+            from_path: row.get(3)?,
+            old_sha256: row.get(4)?,
+            sha256: row.get(5)?,
+            has_diff: row.get::<_, Option<bool>>(6)?.unwrap_or(false)
+        })
+    })?;
+
+    let mut versions = Vec::new();
+    for row in rows {
+        versions.push(row?);
+    }
+    Ok(versions)
+}
+
+// Versions recorded before blobs were kept per event are rebuilt by
+// replaying the stored patches from the closest known version, and only
+// returned if the result hashes to the recorded digest.
+pub fn version_at_event(
+    conn: &Connection,
+    event_id: i64
+) -> Result<Vec<u8>, Box<dyn Error>> {
+
+    let (path_id, target): (i64, Option<String>) = conn.query_row(
+        "SELECT path_id, new_sha256 FROM event WHERE id = ?1",
+        params![event_id],
+        |row| Ok((row.get(0)?, row.get(1)?))
+    )?;
+    let target = match target {
+        Some(target) => target,
+        None => return Err(format!("event {} did not record any content", event_id).into())
+    };
+
+    if let Ok(data) = blob::get(conn, &target) {
+        return Ok(data);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT diff, new_sha256 FROM event
+        WHERE path_id = ?1 AND id <= ?2 AND new_sha256 IS NOT NULL ORDER BY id"
+    )?;
+    let rows = stmt.query_map(params![path_id, event_id], |row| {
+        Ok((row.get::<_, Option<Vec<u8>>>(0)?.unwrap_or_default(), row.get::<_, String>(1)?))
+    })?;
+
+    let empty = Digests::of(&[]).sha256;
+    let mut current: Option<Vec<u8>> = None;
+    for row in rows {
+        let (diff, sha256) = row?;
+
+        if let Ok(data) = blob::get(conn, &sha256) {
+            current = Some(data);
+            continue;
+        }
+
+        current = match current {
+            Some(base) if !diff.is_empty() => Patch::from_bytes(&diff)
+                .ok()
+                .and_then(|patch| apply_bytes(&base, &patch).ok()),
+            Some(base) => Some(base),
+            None if sha256 == empty => Some(Vec::new()),
+            None => None
+        }.filter(|data| Digests::of(data).sha256 == sha256);
+    }
+
+    match current {
+        Some(data) if Digests::of(&data).sha256 == target => Ok(data),
+        _ => Err(format!("the version recorded by event {} can no longer be rebuilt", event_id).into())
+    }
+}
+
+pub fn version_before_event(
+    conn: &Connection,
+    event_id: i64
+) -> Result<Vec<u8>, Box<dyn Error>> {
+
+    let old: Option<String> = conn.query_row(
+        "SELECT old_sha256 FROM event WHERE id = ?1",
+        params![event_id],
+        |row| row.get(0)
+    )?;
+
+    match old {
+        Some(old) => blob::get(conn, &old)
+            .map_err(|_| format!("the version before event {} was not kept", event_id).into()),
+        None => Err(format!("event {} has no previous version", event_id).into())
+    }
+}
+
+// `at` is a UTC timestamp such as "2024-03-01 12:30:00".
+pub fn version_at_time(
+    conn: &Connection,
+    path: &Path,
+    at: &str
+) -> Result<(i64, Vec<u8>), Box<dyn Error>> {
+
+    let mut stmt = conn.prepare(
+        "SELECT event.id, CASE WHEN event.path_id = path.id THEN event.new_sha256 END
+        FROM event INNER JOIN path ON event.path_id = path.id OR event.from_path_id = path.id
+        WHERE path.file_path = ?1 AND event.date_event <= datetime(?2)
+            AND event.type_event NOT IN ('ATTRIB', 'OVERFLOW')
+        ORDER BY event.id DESC LIMIT 1"
+    )?;
+    let last: Option<(i64, Option<String>)> = stmt.query_row(params![path.to_string_lossy(), at], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    }).optional()?;
+
+    match last {
+        Some((event_id, Some(_))) => Ok((event_id, version_at_event(conn, event_id)?)),
+        Some((_, None)) => Err(format!("{} did not exist at {}", path.display(), at).into()),
+        None => Err(format!("no version of {} recorded before {}", path.display(), at).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RootConfig;
+    use crate::testing;
+
+    // Type, path, source path and diff of every event, oldest first.
+    fn events(conn: &Connection) -> Vec<(String, String, Option<String>, Vec<u8>)> {
+        let mut stmt = conn.prepare(
+            "SELECT event.type_event, path.file_path, from_path.file_path, coalesce(event.diff, x'')
+            FROM event INNER JOIN path ON event.path_id = path.id
+            LEFT JOIN path AS from_path ON event.from_path_id = from_path.id ORDER BY event.id"
+        ).unwrap();
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    fn filters(root: &Path) -> Filters {
+        Filters::new(&[RootConfig::new(root.to_path_buf())]).unwrap()
+    }
+
+    #[test]
+    fn rename_records_one_event_with_both_paths() {
+        let conn = testing::database();
+        let root = testing::directory("rename-file");
+        let (from, to) = (root.join("a.txt"), root.join("b.txt"));
+        fs::write(&from, "hello\n").unwrap();
+        check_rec(&conn, &root, &filters(&root)).unwrap();
+
+        fs::rename(&from, &to).unwrap();
+        rename_file(&conn, &from, &to).unwrap();
+
+        let events = events(&conn);
+        assert_eq!(events.len(), 1);
+        let (type_event, path, from_path, diff) = &events[0];
+        assert_eq!(type_event, "RENAME");
+        assert_eq!(path, &to.to_string_lossy());
+        assert_eq!(from_path.as_deref(), Some(&*from.to_string_lossy()));
+        assert!(diff.is_empty());
+        assert!(is_gone(&conn, &from).unwrap());
+        assert!(!is_gone(&conn, &to).unwrap());
+    }
+
+    #[test]
+    fn rename_over_new_content_records_the_changes() {
+        let conn = testing::database();
+        let root = testing::directory("rename-modified");
+        let (from, to) = (root.join("a.txt"), root.join("b.txt"));
+        fs::write(&from, "hello\n").unwrap();
+        check_rec(&conn, &root, &filters(&root)).unwrap();
+
+        fs::write(&to, "goodbye\n").unwrap();
+        fs::remove_file(&from).unwrap();
+        rename_file(&conn, &from, &to).unwrap();
+
+        let diff = String::from_utf8(events(&conn)[0].3.clone()).unwrap();
+        assert!(diff.contains("-hello"));
+        assert!(diff.contains("+goodbye"));
+        assert_eq!(get_last_copy(&conn, &to).unwrap(), b"goodbye\n");
+    }
+
+    #[test]
+    fn rename_dir_renames_every_known_file() {
+        let conn = testing::database();
+        let root = testing::directory("rename-dir");
+        let (from, to) = (root.join("old"), root.join("new"));
+        fs::create_dir_all(from.join("sub")).unwrap();
+        fs::write(from.join("x.txt"), "x\n").unwrap();
+        fs::write(from.join("sub/y.txt"), "y\n").unwrap();
+        check_rec(&conn, &root, &filters(&root)).unwrap();
+
+        fs::rename(&from, &to).unwrap();
+        rename_dir(&conn, &from, &to, &filters(&root)).unwrap();
+
+        let mut renamed: Vec<(String, Option<String>)> = events(&conn).into_iter()
+            .filter(|(type_event, _, _, _)| type_event == "RENAME")
+            .map(|(_, path, from_path, _)| (path, from_path))
+            .collect();
+        renamed.sort();
+        let expected = |name: &str| (to.join(name).to_string_lossy().into_owned(), Some(from.join(name).to_string_lossy().into_owned()));
+        assert!(renamed.contains(&expected("x.txt")));
+        assert!(renamed.contains(&expected("sub/y.txt")));
+        assert!(is_gone(&conn, &from.join("x.txt")).unwrap());
+    }
+
+    #[test]
+    fn overflow_records_the_root_without_content() {
+        let conn = testing::database();
+        let root = testing::directory("overflow");
+
+        record_overflow(&conn, &root).unwrap();
+        record_overflow(&conn, &root).unwrap();
+
+        let (paths, sha256): (i64, Option<String>) = conn.query_row(
+            "SELECT count(*), max(sha256) FROM path", [], |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap();
+        assert_eq!(paths, 1);
+        assert_eq!(sha256, None);
+        let blobs: i64 = conn.query_row("SELECT count(*) FROM blob", [], |row| row.get(0)).unwrap();
+        assert_eq!(blobs, 0);
+        let types: Vec<String> = events(&conn).into_iter().map(|(type_event, _, _, _)| type_event).collect();
+        assert_eq!(types, ["OVERFLOW", "OVERFLOW"]);
+        assert!(version_at_event(&conn, 1).is_err());
+    }
+
+    // Three versions of a.txt, from its creation, on three different days.
+    fn three_versions(conn: &Connection, name: &str) -> PathBuf {
+        let root = testing::directory(name);
+        let path = root.join("a.txt");
+        fs::write(&path, "one\ntwo\n").unwrap();
+        check_file(conn, &path, "CREATE").unwrap();
+        fs::write(&path, "one\n2\n").unwrap();
+        check_file(conn, &path, "MODIFY").unwrap();
+        fs::write(&path, "one\n2\nthree\n").unwrap();
+        check_file(conn, &path, "MODIFY").unwrap();
+        conn.execute("UPDATE event SET date_event = '2024-01-0' || id || ' 12:00:00'", []).unwrap();
+        path
+    }
+
+    #[test]
+    fn versions_are_read_from_their_blobs() {
+        let conn = testing::database();
+        three_versions(&conn, "version-blob");
+
+        assert_eq!(version_at_event(&conn, 1).unwrap(), b"one\ntwo\n");
+        assert_eq!(version_at_event(&conn, 3).unwrap(), b"one\n2\nthree\n");
+        assert_eq!(version_before_event(&conn, 3).unwrap(), b"one\n2\n");
+    }
+
+    #[test]
+    fn missing_versions_are_rebuilt_from_the_patches() {
+        let conn = testing::database();
+        three_versions(&conn, "version-replay");
+        for content in ["one\n2\n", "one\n2\nthree\n"] {
+            conn.execute("DELETE FROM blob WHERE sha256 = ?1", params![Digests::of(content.as_bytes()).sha256]).unwrap();
+        }
+
+        assert_eq!(version_at_event(&conn, 2).unwrap(), b"one\n2\n");
+        assert_eq!(version_at_event(&conn, 3).unwrap(), b"one\n2\nthree\n");
+    }
+
+    #[test]
+    fn a_damaged_patch_is_not_returned() {
+        let conn = testing::database();
+        three_versions(&conn, "version-damaged");
+        conn.execute("DELETE FROM blob WHERE sha256 = ?1", params![Digests::of(b"one\n2\n").sha256]).unwrap();
+        let patch = create_patch_bytes(b"one\ntwo\n", b"one\nother\n").to_bytes();
+        conn.execute("UPDATE event SET diff = ?1 WHERE id = 2", params![patch]).unwrap();
+
+        assert!(version_at_event(&conn, 2).is_err());
+    }
+
+    #[test]
+    fn version_at_time_takes_the_last_event_before() {
+        let conn = testing::database();
+        let path = three_versions(&conn, "version-time");
+
+        assert_eq!(version_at_time(&conn, &path, "2024-01-02 18:00:00").unwrap(), (2, b"one\n2\n".to_vec()));
+        assert_eq!(version_at_time(&conn, &path, "2024-01-03 12:00:00").unwrap().0, 3);
+        assert!(version_at_time(&conn, &path, "2023-12-31 00:00:00").is_err());
+
+        fs::remove_file(&path).unwrap();
+        check_file(&conn, &path, "DELETE").unwrap();
+        conn.execute("UPDATE event SET date_event = '2024-01-04 12:00:00' WHERE id = 4", []).unwrap();
+        assert!(version_at_time(&conn, &path, "2024-01-05 00:00:00").is_err());
+    }
+
+    #[test]
+    fn compare_reports_the_drift_without_recording_it() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let conn = testing::database();
+        let root = testing::directory("compare");
+        fs::create_dir(root.join("sub")).unwrap();
+        for name in ["a.txt", "b.txt", "same.txt"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        check_rec(&conn, &root, &filters(&root)).unwrap();
+
+        fs::write(root.join("a.txt"), "changed").unwrap();
+        fs::remove_file(root.join("b.txt")).unwrap();
+        fs::write(root.join("c.txt"), "new").unwrap();
+        fs::set_permissions(root.join("sub"), fs::Permissions::from_mode(0o700)).unwrap();
+        let mut drift = Drift::default();
+        compare(&conn, &root, &filters(&root), &mut drift).unwrap();
+
+        assert_eq!(drift.added, [root.join("c.txt")]);
+        assert_eq!(drift.removed, [root.join("b.txt")]);
+        assert_eq!(drift.changed.len(), 1);
+        assert_eq!(drift.changed[0].0, root.join("a.txt"));
+        assert!(drift.changed[0].1[0].starts_with("sha256: "));
+        assert_eq!(drift.metadata.len(), 1);
+        assert_eq!(drift.metadata[0].0, root.join("sub"));
+        assert!(events(&conn).is_empty());
+    }
+
+    #[test]
+    fn compare_finds_nothing_on_an_unchanged_tree() {
+        let conn = testing::database();
+        let root = testing::directory("compare-clean");
+        fs::write(root.join("a.txt"), "a").unwrap();
+        check_rec(&conn, &root, &filters(&root)).unwrap();
+
+        let mut drift = Drift::default();
+        compare(&conn, &root, &filters(&root), &mut drift).unwrap();
+
+        assert!(drift.is_empty());
+    }
+
+    #[test]
+    fn unpaired_moves_are_recorded_as_moved_out_and_in() {
+        let conn = testing::database();
+        let root = testing::directory("moved-out-in");
+        let path = root.join("a.txt");
+        fs::write(&path, "hello\n").unwrap();
+        check_rec(&conn, &root, &filters(&root)).unwrap();
+
+        fs::remove_file(&path).unwrap();
+        check_file(&conn, &path, "MOVED_OUT").unwrap();
+        fs::write(&path, "hello again\n").unwrap();
+        check_file(&conn, &path, "MOVED_IN").unwrap();
+
+        let types: Vec<String> = events(&conn).into_iter().map(|(type_event, _, _, _)| type_event).collect();
+        assert_eq!(types, ["MOVED_OUT", "MOVED_IN"]);
+        assert!(!is_gone(&conn, &path).unwrap());
+    }
+
+    #[test]
+    fn a_mode_changed_with_the_content_is_reported() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let conn = testing::database();
+        let root = testing::directory("offline-chmod-edit");
+        let path = root.join("a.sh");
+        fs::write(&path, "echo a\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        check_rec(&conn, &root, &filters(&root)).unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o4755)).unwrap();
+        fs::write(&path, "echo a\necho b\n").unwrap();
+        reconcile(&conn, &root, &filters(&root), true).unwrap();
+
+        let recorded = events(&conn);
+        let types: Vec<&str> = recorded.iter().map(|(type_event, _, _, _)| type_event.as_str()).collect();
+        assert_eq!(types, ["OFFLINE_MODIFY", "ATTRIB"]);
+        assert_eq!(String::from_utf8_lossy(&recorded[1].3), "mode: 0644 -> 4755");
+
+        // Nothing is left to report on the next pass.
+        reconcile(&conn, &root, &filters(&root), true).unwrap();
+        check_file(&conn, &path, "MAYBE_ATTRIB").unwrap();
+        assert_eq!(events(&conn).len(), 2);
+    }
+}
@@ -0,0 +1,104 @@
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::config::EventLogConfig;
+use crate::sink::EventRecord;
+
+
+
+// The open file and the time it was started.
+struct Current {
+    file: File,
+    started: SystemTime
+}
+
+// One JSON object per line, the same fields as the webhook payload, for
+// Filebeat, Vector or anything else that tails files.
+pub struct EventLog {
+    config: EventLogConfig,
+    current: Mutex<Option<Current>>
+}
+
+impl EventLog {
+    pub fn new(config: &EventLogConfig) -> Self {
+        Self {
+            config: config.clone(),
+            current: Mutex::new(None)
+        }
+    }
+
+    fn rotated(&self, index: u32) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn open(&self) -> Result<Current, Box<dyn Error>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)?;
+        let meta = file.metadata()?;
+
+        Ok(Current {
+            file,
+            started: meta.created().unwrap_or_else(|_| SystemTime::now())
+        })
+    }
+
+    // events.ndjson becomes events.ndjson.1, events.ndjson.1 becomes
+    // events.ndjson.2, and so on; the oldest one is overwritten.
+    fn rotate(&self) -> Result<(), Box<dyn Error>> {
+        for index in (1..self.config.keep).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated(index + 1))?;
+            }
+        }
+        fs::rename(&self.config.path, self.rotated(1))?;
+
+        Ok(())
+    }
+
+    pub fn write(&self, record: &EventRecord) -> Result<(), Box<dyn Error>> {
+        let line = format!("{}\n", serde_json::to_string(record)?);
+        let mut current = self.current.lock().map_err(|e| e.to_string())?;
+
+        // Another process writing the same log may have rotated it.
+        if let Some(open) = current.as_ref() {
+            let moved = match fs::metadata(&self.config.path) {
+                Ok(meta) => meta.ino() != open.file.metadata()?.ino(),
+                Err(_) => true
+            };
+            if moved {
+                *current = None;
+            }
+        }
+        if current.is_none() {
+            *current = Some(self.open()?);
+        }
+
+        let size = current.as_ref().map_or(Ok(0), |open| open.file.metadata().map(|meta| meta.len()))?;
+        let age = current.as_ref()
+            .and_then(|open| open.started.elapsed().ok())
+            .unwrap_or_default();
+        let too_big = size + line.len() as u64 > self.config.max_size_mb * 1024 * 1024;
+        let too_old = age >= Duration::from_secs(self.config.max_age_hours * 3600);
+        if size > 0 && (too_big || too_old) {
+            *current = None;
+            self.rotate()?;
+            *current = Some(self.open()?);
+        }
+
+        if let Some(open) = current.as_mut() {
+            open.file.write_all(line.as_bytes())?;
+        }
+
+        Ok(())
+    }
+}
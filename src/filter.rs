@@ -0,0 +1,141 @@
+use std::path::{Path, PathBuf};
+use std::error::Error;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::config::RootConfig;
+
+
+
+struct RootFilter {
+    root: PathBuf,
+    include: Option<Gitignore>,
+    exclude: Gitignore
+}
+
+// The include and exclude rules of every root, written like .gitignore lines
+// relative to the root: `*.swp`, `.cache/`, `/logs/**`, `!keep.log`...
+pub struct Filters {
+    roots: Vec<RootFilter>
+}
+
+fn build(
+    root: &Path,
+    patterns: &[String]
+) -> Result<Gitignore, Box<dyn Error>> {
+
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns {
+        builder.add_line(None, pattern)
+            .map_err(|e| format!("invalid pattern \"{}\": {}", pattern, e))?;
+    }
+
+    Ok(builder.build()?)
+}
+
+impl Filters {
+    pub fn new(roots: &[RootConfig]) -> Result<Self, Box<dyn Error>> {
+        let mut filters = Vec::new();
+
+        for root in roots {
+            filters.push(RootFilter {
+                root: root.path.clone(),
+                include: if root.include.is_empty() {
+                    None
+                } else {
+                    Some(build(&root.path, &root.include)?)
+                },
+                exclude: build(&root.path, &root.exclude)?
+            });
+        }
+
+        Ok(Self { roots: filters })
+    }
+
+    fn root_of(&self, path: &Path) -> Option<&RootFilter> {
+        self.roots.iter().find(|filter| path != filter.root && path.starts_with(&filter.root))
+    }
+
+    // Excluded directories are neither watched nor walked, so nothing below
+    // them is ever seen.
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        match self.root_of(path) {
+            Some(filter) => filter.exclude.matched_path_or_any_parents(path, is_dir).is_ignore(),
+            None => false
+        }
+    }
+
+    // When a root has include rules, only the paths they match are recorded;
+    // the other directories are still walked to find them.
+    pub fn is_recorded(&self, path: &Path, is_dir: bool) -> bool {
+        match self.root_of(path) {
+            Some(filter) => {
+                if filter.exclude.matched_path_or_any_parents(path, is_dir).is_ignore() {
+                    return false;
+                }
+                match &filter.include {
+                    Some(include) => include.matched_path_or_any_parents(path, is_dir).is_ignore(),
+                    None => true
+                }
+            }
+            None => true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(include: &[&str], exclude: &[&str]) -> Filters {
+        let mut root = RootConfig::new(PathBuf::from("/srv/www"));
+        root.include = include.iter().map(|pattern| pattern.to_string()).collect();
+        root.exclude = exclude.iter().map(|pattern| pattern.to_string()).collect();
+        Filters::new(&[root]).unwrap()
+    }
+
+    #[test]
+    fn exclude_rules_read_like_gitignore() {
+        let filters = filters(&[], &["*.swp", ".cache/", "/logs/**", "!logs/keep.log"]);
+
+        assert!(filters.is_recorded(Path::new("/srv/www/index.php"), false));
+        assert!(!filters.is_recorded(Path::new("/srv/www/css/.site.css.swp"), false));
+        assert!(filters.is_excluded(Path::new("/srv/www/app/.cache"), true));
+        assert!(!filters.is_excluded(Path::new("/srv/www/app/.cache"), false));
+        assert!(!filters.is_recorded(Path::new("/srv/www/app/.cache/entry"), false));
+        assert!(!filters.is_recorded(Path::new("/srv/www/logs/access.log"), false));
+        assert!(filters.is_recorded(Path::new("/srv/www/logs/keep.log"), false));
+        assert!(filters.is_recorded(Path::new("/srv/www/app/logs/access.log"), false));
+    }
+
+    #[test]
+    fn include_rules_only_record_what_they_match() {
+        let filters = filters(&["*.php", "config/"], &["vendor/"]);
+
+        assert!(filters.is_recorded(Path::new("/srv/www/index.php"), false));
+        assert!(filters.is_recorded(Path::new("/srv/www/app/config/db.ini"), false));
+        assert!(!filters.is_recorded(Path::new("/srv/www/app/style.css"), false));
+        assert!(!filters.is_recorded(Path::new("/srv/www/vendor/lib.php"), false));
+        // Walked to find the included files, but not recorded.
+        assert!(!filters.is_excluded(Path::new("/srv/www/app"), true));
+        assert!(!filters.is_recorded(Path::new("/srv/www/app"), true));
+    }
+
+    #[test]
+    fn rules_only_apply_below_their_root() {
+        let filters = filters(&["*.php"], &["*"]);
+
+        assert!(filters.is_recorded(Path::new("/srv/www"), true));
+        assert!(!filters.is_excluded(Path::new("/srv/www"), true));
+        assert!(filters.is_recorded(Path::new("/srv/other/a.txt"), false));
+        assert!(filters.is_recorded(Path::new("/srv/www2/a.txt"), false));
+    }
+
+    #[test]
+    fn invalid_patterns_are_reported() {
+        let mut root = RootConfig::new(PathBuf::from("/srv/www"));
+        root.exclude = vec![String::from("[z-a]")];
+
+        let error = Filters::new(&[root]).err().unwrap().to_string();
+        assert!(error.contains("[z-a]"));
+    }
+}
@@ -0,0 +1,32 @@
+use sha2::{Digest, Sha256};
+
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Digests {
+    pub sha256: String,
+    pub blake3: Option<String>
+}
+
+impl Digests {
+    pub fn of(data: &[u8]) -> Self {
+        Self {
+            sha256: to_hex(&Sha256::digest(data)),
+            blake3: blake3_hex(data)
+        }
+    }
+}
+
+#[cfg(feature = "blake3")]
+fn blake3_hex(data: &[u8]) -> Option<String> {
+    Some(blake3::hash(data).to_hex().to_string())
+}
+
+#[cfg(not(feature = "blake3"))]
+fn blake3_hex(_data: &[u8]) -> Option<String> {
+    None
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,25 @@
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+
+use crate::sink::EventRecord;
+
+
+
+// A browser that falls this far behind misses events on the bus and reads
+// them back from the database instead.
+const CAPACITY: usize = 256;
+
+static BUS: OnceLock<broadcast::Sender<EventRecord>> = OnceLock::new();
+
+fn bus() -> &'static broadcast::Sender<EventRecord> {
+    BUS.get_or_init(|| broadcast::channel(CAPACITY).0)
+}
+
+// Sending fails only when nobody is listening.
+pub fn publish(record: &EventRecord) {
+    let _ = bus().send(record.clone());
+}
+
+pub fn subscribe() -> broadcast::Receiver<EventRecord> {
+    bus().subscribe()
+}
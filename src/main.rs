@@ -1,20 +1,37 @@
-use std::sync::{Arc, Mutex};
+use std::error::Error;
+use clap::Parser;
 
 mod web;
+mod api;
+mod auth;
 mod path;
 mod app;
 mod event_dir;
 mod event_file;
 mod watcher;
+mod metadata;
+mod hash;
+mod blob;
+mod restore;
+mod config;
+mod filter;
+mod cli;
+mod session;
+mod sink;
+mod webhook;
+mod syslog;
+mod event_log;
+mod live;
+mod search;
+mod diff_view;
+mod tree;
+#[cfg(test)]
+mod testing;
 
-use crate::web::start_web;
-use crate::app::AppFIM;
+use crate::cli::{run, Cli};
 
 
 
-fn main() -> std::io::Result<()> {
-    let app_fim = Arc::new(Mutex::new(AppFIM::new()));
-    start_web(app_fim)?;
-
-    return Ok(());
-}
\ No newline at end of file
+fn main() -> Result<(), Box<dyn Error>> {
+    run(Cli::parse())
+}
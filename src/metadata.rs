@@ -0,0 +1,132 @@
+use std::fs;
+use std::path::Path;
+use std::os::unix::fs::MetadataExt;
+use nix::unistd::{Group, User, Gid, Uid};
+
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileMeta {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub mtime: i64,
+    pub ctime: i64,
+    pub inode: u64,
+    pub nlink: u64
+}
+
+impl FileMeta {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let metadata = fs::symlink_metadata(path).ok()?;
+
+        Some(Self {
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size(),
+            mtime: metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+            ctime: metadata.ctime() * 1_000_000_000 + metadata.ctime_nsec(),
+            inode: metadata.ino(),
+            nlink: metadata.nlink()
+        })
+    }
+
+    // One line per attribute that differs, e.g. "mode: 0644 -> 4755". A
+    // change of ctime alone is only reported when nothing else moved, since
+    // that is how ACL and extended attribute changes show up.
+    pub fn changes(&self, new: &FileMeta) -> Vec<String> {
+        let mut changes = self.ownership_changes(new);
+
+        if self.size != new.size {
+            changes.push(format!("size: {} -> {}", self.size, new.size));
+        }
+        if self.mtime != new.mtime {
+            changes.push(format!("mtime: {} -> {}", format_time(self.mtime), format_time(new.mtime)));
+        }
+        if self.inode != new.inode {
+            changes.push(format!("inode: {} -> {}", self.inode, new.inode));
+        }
+        if self.nlink != new.nlink {
+            changes.push(format!("nlink: {} -> {}", self.nlink, new.nlink));
+        }
+        if changes.is_empty() && self.ctime != new.ctime {
+            changes.push(format!("ctime: {} -> {} (ACL or extended attributes)", format_time(self.ctime), format_time(new.ctime)));
+        }
+
+        changes
+    }
+
+    // Only the mode and the owner, which an edit of the content leaves as
+    // they were.
+    pub fn ownership_changes(&self, new: &FileMeta) -> Vec<String> {
+        let mut changes = Vec::new();
+
+        if self.mode != new.mode {
+            changes.push(format!("mode: {} -> {}", format_mode(self.mode), format_mode(new.mode)));
+        }
+        if self.uid != new.uid {
+            changes.push(format!("uid: {} -> {}", format_uid(self.uid), format_uid(new.uid)));
+        }
+        if self.gid != new.gid {
+            changes.push(format!("gid: {} -> {}", format_gid(self.gid), format_gid(new.gid)));
+        }
+
+        changes
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        vec![
+            format!("mode: unknown -> {}", format_mode(self.mode)),
+            format!("uid: unknown -> {}", format_uid(self.uid)),
+            format!("gid: unknown -> {}", format_gid(self.gid)),
+            format!("size: unknown -> {}", self.size),
+            format!("mtime: unknown -> {}", format_time(self.mtime)),
+            format!("inode: unknown -> {}", self.inode),
+            format!("nlink: unknown -> {}", self.nlink)
+        ]
+    }
+}
+
+fn format_mode(mode: u32) -> String {
+    format!("{:04o}", mode & 0o7777)
+}
+
+fn format_uid(uid: u32) -> String {
+    match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(user)) => format!("{} ({})", uid, user.name),
+        _ => uid.to_string()
+    }
+}
+
+fn format_gid(gid: u32) -> String {
+    match Group::from_gid(Gid::from_raw(gid)) {
+        Ok(Some(group)) => format!("{} ({})", gid, group.name),
+        _ => gid.to_string()
+    }
+}
+
+// UTC, nanosecond precision: "2024-03-01 12:30:05.123456789".
+pub fn format_time(nanos: i64) -> String {
+    let secs = nanos.div_euclid(1_000_000_000);
+    let nsec = nanos.rem_euclid(1_000_000_000);
+    let days = secs.div_euclid(86_400);
+    let rem = secs.rem_euclid(86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:09}",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60, nsec
+    )
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{chown, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::error::Error;
+use std::process;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::event_file::{record_restore, version_at_event, version_before_event};
+
+
+
+const TEMPORARY_MARKER: &str = ".fim-restore-";
+
+// How long the monitor of this process leaves a temporary file alone: it
+// sees the rename well before, unless it is not running.
+const TEMPORARY_TTL: Duration = Duration::from_secs(60);
+
+// The temporary files this process writes. Only these are left out of the
+// events, never a file that merely has the same name.
+static TEMPORARY: OnceLock<Mutex<HashMap<PathBuf, Instant>>> = OnceLock::new();
+
+struct Ownership {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>
+}
+
+// The metadata of the version being restored: the one recorded by the event
+// itself, or by the last event of the same file before it when restoring
+// the previous version.
+fn get_ownership(
+    conn: &Connection,
+    event_id: i64,
+    before: bool
+) -> Result<Ownership, Box<dyn Error>> {
+
+    let query = if before {
+        "SELECT mode, uid, gid FROM event
+        WHERE path_id = (SELECT path_id FROM event WHERE id = ?1) AND id < ?1 AND mode IS NOT NULL
+        ORDER BY id DESC LIMIT 1"
+    } else {
+        "SELECT mode, uid, gid FROM event WHERE id = ?1 AND mode IS NOT NULL"
+    };
+
+    let ownership = conn.query_row(query, params![event_id], |row| {
+        Ok(Ownership {
+            mode: row.get(0)?,
+            uid: row.get(1)?,
+            gid: row.get(2)?
+        })
+    }).optional()?;
+
+    match ownership {
+        Some(ownership) => Ok(ownership),
+        None => Ok(conn.query_row(
+            "SELECT path.mode, path.uid, path.gid FROM event INNER JOIN path ON event.path_id = path.id WHERE event.id = ?1",
+            params![event_id],
+            |row| Ok(Ownership {
+                mode: row.get(0)?,
+                uid: row.get(1)?,
+                gid: row.get(2)?
+            })
+        )?)
+    }
+}
+
+fn temporary_files() -> MutexGuard<'static, HashMap<PathBuf, Instant>> {
+    let mut files = TEMPORARY.get_or_init(|| Mutex::new(HashMap::new())).lock().unwrap();
+    files.retain(|_, written| written.elapsed() < TEMPORARY_TTL);
+    files
+}
+
+pub fn is_temporary(path: &Path) -> bool {
+    temporary_files().contains_key(path)
+}
+
+// Once renamed over its target, the name is no longer left alone.
+pub fn take_temporary(path: &Path) -> bool {
+    temporary_files().remove(path).is_some()
+}
+
+// Written next to the target so that the final rename stays on the same
+// filesystem and the file is never seen half written.
+fn write_atomic(
+    path: &Path,
+    data: &[u8],
+    ownership: &Ownership
+) -> Result<(), Box<dyn Error>> {
+
+    let parent = path.parent().ok_or("cannot restore a filesystem root")?;
+    let file_name = path.file_name().ok_or("cannot restore a path without a file name")?;
+    let tmp_path = parent.join(format!(".{}{}{}", file_name.to_string_lossy(), TEMPORARY_MARKER, process::id()));
+    temporary_files().insert(tmp_path.clone(), Instant::now());
+
+    let result = (|| -> Result<(), Box<dyn Error>> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+
+        if ownership.uid.is_some() || ownership.gid.is_some() {
+            chown(&tmp_path, ownership.uid, ownership.gid)?;
+        }
+        if let Some(mode) = ownership.mode {
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+
+        fs::rename(&tmp_path, path)?;
+        File::open(parent)?.sync_all()?;
+        Ok(())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+pub fn restore(
+    conn: &Connection,
+    event_id: i64,
+    before: bool,
+    operator: &str
+) -> Result<PathBuf, Box<dyn Error>> {
+
+    let path: String = conn.query_row(
+        "SELECT path.file_path FROM event INNER JOIN path ON event.path_id = path.id WHERE event.id = ?1",
+        params![event_id],
+        |row| row.get(0)
+    )?;
+    let path = PathBuf::from(path);
+
+    let data = if before {
+        version_before_event(conn, event_id)?
+    } else {
+        version_at_event(conn, event_id)?
+    };
+    let ownership = get_ownership(conn, event_id, before)?;
+
+    write_atomic(&path, &data, &ownership)?;
+    record_restore(conn, &path, operator)?;
+
+    println!("Fichier restauré : {:?} (événement {}, par {})", path, event_id, operator);
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_file::check_file;
+    use crate::testing;
+
+    #[test]
+    fn restore_writes_the_version_and_records_one_event() {
+        let conn = testing::database();
+        let root = testing::directory("restore");
+        let path = root.join("a.txt");
+        fs::write(&path, "first\n").unwrap();
+        check_file(&conn, &path, "CREATE").unwrap();
+        fs::write(&path, "second\n").unwrap();
+        check_file(&conn, &path, "MODIFY").unwrap();
+
+        restore(&conn, 2, true, "test").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"first\n");
+        let (type_event, operator): (String, String) = conn.query_row(
+            "SELECT type_event, operator FROM event WHERE id = 3", [], |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap();
+        assert_eq!((type_event.as_str(), operator.as_str()), ("RESTORE", "test"));
+        let names: Vec<_> = fs::read_dir(&root).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, ["a.txt"]);
+    }
+
+    #[test]
+    fn only_the_files_written_here_are_temporary() {
+        let root = testing::directory("restore-temporary");
+        let path = root.join("b.txt");
+        let tmp_path = root.join(format!(".b.txt{}{}", TEMPORARY_MARKER, process::id()));
+        let lookalike = root.join(format!(".c.txt{}{}", TEMPORARY_MARKER, process::id()));
+        assert!(!is_temporary(&tmp_path));
+
+        write_atomic(&path, b"data\n", &Ownership { mode: None, uid: None, gid: None }).unwrap();
+
+        assert!(is_temporary(&tmp_path));
+        assert!(!is_temporary(&lookalike));
+        assert!(!is_temporary(&path));
+        assert!(take_temporary(&tmp_path));
+        assert!(!is_temporary(&tmp_path));
+        assert!(!take_temporary(&tmp_path));
+    }
+}
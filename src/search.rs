@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rusqlite::types::Value;
+
+
+
+// What every event query joins, so that filters can name both paths.
+pub const EVENT_TABLES: &str = "event INNER JOIN path ON event.path_id = path.id
+    LEFT JOIN path AS from_path ON event.from_path_id = from_path.id";
+
+// Filters on the events, shared by the web page, the API and the command
+// line. Dates are UTC, in any form SQLite's datetime() reads.
+#[derive(Debug, Clone, Default)]
+pub struct EventSearch {
+    pub type_event: Option<String>,
+    // The file itself, or everything below a directory.
+    pub path: Option<String>,
+    // A monitored root, read the same way as path.
+    pub root: Option<String>,
+    // Part of the path.
+    pub contains: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    // Anywhere in the paths, the type, the hashes or the operator, ignoring
+    // case.
+    pub text: Option<String>,
+    // Only the events that came after this one.
+    pub after: Option<i64>
+}
+
+// Both ends of the range holding a path and everything below it: '0' comes
+// right after '/', so the index on path(file_path) can be used.
+fn prefix_range(path: &str) -> (String, String, String) {
+    let dir = path.trim_end_matches('/');
+    (dir.to_string(), format!("{}/", dir), format!("{}0", dir))
+}
+
+impl EventSearch {
+    // From a query string, where an empty field means no filter.
+    pub fn from_query(query: &HashMap<String, String>) -> Self {
+        let get = |name: &str| query.get(name)
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .map(String::from);
+
+        Self {
+            type_event: get("type").map(|type_event| type_event.to_uppercase()),
+            path: get("path"),
+            root: get("root"),
+            contains: get("contains"),
+            since: get("since"),
+            until: get("until"),
+            text: get("q"),
+            after: None
+        }
+    }
+
+    // The reverse of from_query, for the links between pages.
+    pub fn query_string(&self) -> String {
+        let fields = [
+            ("type", &self.type_event),
+            ("path", &self.path),
+            ("root", &self.root),
+            ("contains", &self.contains),
+            ("since", &self.since),
+            ("until", &self.until),
+            ("q", &self.text)
+        ];
+
+        fields.iter()
+            .filter_map(|(name, value)| value.as_ref().map(|value| format!("{}={}", name, utf8_percent_encode(value, NON_ALPHANUMERIC))))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.query_string().is_empty()
+    }
+
+    // The WHERE clause to put after EVENT_TABLES, with the values of its
+    // placeholders in order.
+    pub fn clause(&self) -> (String, Vec<Value>) {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+
+        if let Some(type_event) = &self.type_event {
+            conditions.push("event.type_event = ?");
+            values.push(Value::Text(type_event.clone()));
+        }
+        for path in [&self.path, &self.root].into_iter().flatten() {
+            let (dir, start, end) = prefix_range(path);
+            conditions.push("(path.file_path = ? OR (path.file_path >= ? AND path.file_path < ?))");
+            values.extend([Value::Text(dir), Value::Text(start), Value::Text(end)]);
+        }
+        if let Some(contains) = &self.contains {
+            conditions.push("instr(path.file_path, ?) > 0");
+            values.push(Value::Text(contains.clone()));
+        }
+        if let Some(since) = &self.since {
+            conditions.push("event.date_event >= datetime(?)");
+            values.push(Value::Text(since.clone()));
+        }
+        if let Some(until) = &self.until {
+            conditions.push("event.date_event <= datetime(?)");
+            values.push(Value::Text(until.clone()));
+        }
+        if let Some(text) = &self.text {
+            conditions.push("instr(lower(path.file_path || ' ' || coalesce(from_path.file_path, '') || ' ' || event.type_event || ' '
+                || coalesce(event.old_sha256, '') || ' ' || coalesce(event.new_sha256, '') || ' ' || coalesce(event.operator, '')), lower(?)) > 0");
+            values.push(Value::Text(text.clone()));
+        }
+        if let Some(after) = self.after {
+            conditions.push("event.id > ?");
+            values.push(Value::Integer(after));
+        }
+
+        if conditions.is_empty() {
+            (String::new(), values)
+        } else {
+            (format!("WHERE {}", conditions.join(" AND ")), values)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::{params, params_from_iter, Connection};
+    use crate::testing;
+
+    fn add(conn: &Connection, type_event: &str, path: &str, date: &str) {
+        conn.execute("INSERT OR IGNORE INTO path (file_path) VALUES (?1)", params![path]).unwrap();
+        conn.execute(
+            "INSERT INTO event (type_event, date_event, path_id) SELECT ?1, ?2, id FROM path WHERE file_path = ?3",
+            params![type_event, date, path]
+        ).unwrap();
+    }
+
+    fn database() -> Connection {
+        let conn = testing::database();
+        add(&conn, "CREATE", "/srv", "2026-10-01 08:00:00");
+        add(&conn, "MODIFY", "/srv/www/index.php", "2026-10-02 08:00:00");
+        add(&conn, "DELETE", "/srv/www/old.php", "2026-10-03 08:00:00");
+        add(&conn, "MODIFY", "/srv0/data", "2026-10-04 08:00:00");
+        add(&conn, "CREATE", "/srv-backup/dump.sql", "2026-10-05 08:00:00");
+        add(&conn, "MODIFY", "/etc/passwd", "2026-10-06 08:00:00");
+        conn
+    }
+
+    fn paths(conn: &Connection, search: &EventSearch) -> Vec<String> {
+        let (filter, values) = search.clause();
+        let mut stmt = conn.prepare(&format!("SELECT path.file_path FROM {} {} ORDER BY event.id", EVENT_TABLES, filter)).unwrap();
+        let paths = stmt.query_map(params_from_iter(&values), |row| row.get(0)).unwrap();
+        paths.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    fn path(path: &str) -> EventSearch {
+        EventSearch { path: Some(path.to_string()), ..EventSearch::default() }
+    }
+
+    #[test]
+    fn prefix_range_stops_at_the_directory() {
+        assert_eq!(prefix_range("/srv"), (String::from("/srv"), String::from("/srv/"), String::from("/srv0")));
+        assert_eq!(prefix_range("/srv/"), prefix_range("/srv"));
+        assert_eq!(prefix_range("/"), (String::new(), String::from("/"), String::from("0")));
+    }
+
+    #[test]
+    fn a_path_matches_itself_and_what_is_below() {
+        let conn = database();
+
+        assert_eq!(paths(&conn, &path("/srv")), ["/srv", "/srv/www/index.php", "/srv/www/old.php"]);
+        assert_eq!(paths(&conn, &path("/srv/")), paths(&conn, &path("/srv")));
+        assert_eq!(paths(&conn, &path("/srv0")), ["/srv0/data"]);
+        assert_eq!(paths(&conn, &path("/srv/www/index.php")), ["/srv/www/index.php"]);
+        assert_eq!(paths(&conn, &path("/sr")), Vec::<String>::new());
+        assert_eq!(paths(&conn, &path("/")).len(), 6);
+    }
+
+    #[test]
+    fn filters_add_up() {
+        let conn = database();
+
+        assert_eq!(paths(&conn, &EventSearch::default()).len(), 6);
+        let search = EventSearch {
+            type_event: Some(String::from("MODIFY")),
+            root: Some(String::from("/srv")),
+            ..EventSearch::default()
+        };
+        assert_eq!(paths(&conn, &search), ["/srv/www/index.php"]);
+
+        let search = EventSearch {
+            since: Some(String::from("2026-10-03")),
+            until: Some(String::from("2026-10-05T08:00:00Z")),
+            ..EventSearch::default()
+        };
+        assert_eq!(paths(&conn, &search), ["/srv/www/old.php", "/srv0/data", "/srv-backup/dump.sql"]);
+
+        let search = EventSearch { contains: Some(String::from("srv")), text: Some(String::from("create")), ..EventSearch::default() };
+        assert_eq!(paths(&conn, &search), ["/srv", "/srv-backup/dump.sql"]);
+
+        let search = EventSearch { after: Some(5), ..EventSearch::default() };
+        assert_eq!(paths(&conn, &search), ["/etc/passwd"]);
+    }
+
+    #[test]
+    fn the_query_string_comes_back() {
+        let query: HashMap<String, String> = [("type", "modify"), ("path", " /srv/a b "), ("q", ""), ("page", "2")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let search = EventSearch::from_query(&query);
+
+        assert_eq!(search.type_event.as_deref(), Some("MODIFY"));
+        assert_eq!(search.path.as_deref(), Some("/srv/a b"));
+        assert_eq!(search.text, None);
+        assert_eq!(search.query_string(), "type=MODIFY&path=%2Fsrv%2Fa%20b");
+        assert!(!search.is_empty());
+        assert!(EventSearch::default().is_empty());
+    }
+}
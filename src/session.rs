@@ -0,0 +1,403 @@
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::error::Error;
+use std::process;
+use std::time::{Duration, Instant};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::config::RootConfig;
+use crate::sink;
+
+
+
+// How often a running session proves it is still alive.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Past this, the heartbeat of a RUNNING session no longer counts as
+// monitoring.
+const HEARTBEAT_TIMEOUT_SECONDS: i64 = 60;
+
+thread_local! {
+    // The session of the monitor running on this thread and its last
+    // heartbeat, so that the scans it runs keep it alive as they go.
+    static CURRENT: Cell<Option<(i64, Instant)>> = const { Cell::new(None) };
+}
+
+#[derive(Serialize)]
+pub struct Span {
+    pub start: String,
+    pub end: String,
+    pub seconds: i64,
+    // RUNNING, STOPPED or CRASHED for monitored spans, GAP in between.
+    pub status: String
+}
+
+pub struct Coverage {
+    pub spans: Vec<Span>,
+    // From the start of the first session until now.
+    pub total_seconds: i64,
+    pub gap_seconds: i64
+}
+
+impl Span {
+    pub fn duration(&self) -> String {
+        format_duration(self.seconds)
+    }
+}
+
+impl Coverage {
+    pub fn percent(&self) -> f64 {
+        if self.total_seconds <= 0 {
+            return 100.0;
+        }
+        100.0 * (self.total_seconds - self.gap_seconds) as f64 / self.total_seconds as f64
+    }
+}
+
+fn is_alive(pid: i64) -> bool {
+    kill(Pid::from_raw(pid as i32), None) != Err(Errno::ESRCH)
+}
+
+// Sessions left RUNNING by a monitor that died end at their last heartbeat,
+// the last moment it was known to be watching. A busy monitor can be late
+// with its heartbeat, so on this host only a process that is gone counts;
+// the process of another host cannot be seen, only its silence.
+pub fn mark_crashed(
+    conn: &Connection
+) -> Result<(), Box<dyn Error>> {
+
+    let mut stmt = conn.prepare(
+        "SELECT id, pid, host, strftime('%s', 'now') - strftime('%s', heartbeat_at) FROM session WHERE status = 'RUNNING'"
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, Option<String>>(2)?, row.get::<_, i64>(3)?))
+    })?;
+
+    let hostname = sink::hostname();
+    for row in rows {
+        let (id, pid, host, silence) = row?;
+        let crashed = match host {
+            Some(host) if host != hostname => silence > HEARTBEAT_TIMEOUT_SECONDS,
+            _ => !is_alive(pid)
+        };
+        if crashed {
+            println!("Session {} interrompue (processus {})", id, pid);
+            conn.execute(
+                "UPDATE session SET status = 'CRASHED', stopped_at = heartbeat_at WHERE id = ?1",
+                params![id],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn start(
+    conn: &Connection,
+    roots: &[RootConfig]
+) -> Result<i64, Box<dyn Error>> {
+
+    mark_crashed(conn)?;
+
+    conn.execute(
+        "INSERT INTO session (status, pid, host) VALUES ('RUNNING', ?1, ?2)",
+        params![process::id(), sink::hostname()],
+    )?;
+    let id = conn.last_insert_rowid();
+    CURRENT.set(Some((id, Instant::now())));
+
+    for root in roots {
+        conn.execute(
+            "INSERT INTO session_root (session_id, root) VALUES (?1, ?2)",
+            params![id, root.path.to_string_lossy()],
+        )?;
+    }
+
+    Ok(id)
+}
+
+pub fn heartbeat(
+    conn: &Connection,
+    id: i64
+) -> Result<(), Box<dyn Error>> {
+
+    conn.execute(
+        "UPDATE session SET heartbeat_at = CURRENT_TIMESTAMP WHERE id = ?1",
+        params![id],
+    )?;
+
+    Ok(())
+}
+
+// For the monitor loop and the scans, which call it as often as they
+// like: it only writes when the interval has passed.
+pub fn beat(
+    conn: &Connection
+) -> Result<(), Box<dyn Error>> {
+
+    if let Some((id, last)) = CURRENT.get() {
+        if last.elapsed() >= HEARTBEAT_INTERVAL {
+            heartbeat(conn, id)?;
+            CURRENT.set(Some((id, Instant::now())));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn beat_due() -> bool {
+    CURRENT.get().is_some_and(|(_, last)| last.elapsed() >= HEARTBEAT_INTERVAL)
+}
+
+pub fn stop(
+    conn: &Connection,
+    id: i64,
+    status: &str
+) -> Result<(), Box<dyn Error>> {
+
+    CURRENT.set(None);
+    conn.execute(
+        "UPDATE session SET status = ?1, stopped_at = CURRENT_TIMESTAMP, heartbeat_at = CURRENT_TIMESTAMP WHERE id = ?2",
+        params![status, id],
+    )?;
+
+    Ok(())
+}
+
+// Every root that was ever monitored.
+pub fn roots(
+    conn: &Connection
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+
+    let mut stmt = conn.prepare("SELECT DISTINCT root FROM session_root ORDER BY root")?;
+    let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+
+    let mut roots = Vec::new();
+    for row in rows {
+        roots.push(PathBuf::from(row?));
+    }
+    Ok(roots)
+}
+
+// The monitored spans of a root from its first session until now, with a
+// GAP span wherever no session was watching it.
+pub fn coverage(
+    conn: &Connection,
+    root: &Path
+) -> Result<Coverage, Box<dyn Error>> {
+
+    // A RUNNING session that stopped beating is only trusted until its last
+    // heartbeat, even before the next start marks it CRASHED.
+    let mut stmt = conn.prepare(
+        "WITH spans AS (
+            SELECT session.id, session.started_at, session.status,
+                CASE WHEN session.status = 'RUNNING' AND strftime('%s', 'now') - strftime('%s', session.heartbeat_at) <= ?2
+                    THEN CURRENT_TIMESTAMP
+                    ELSE COALESCE(session.stopped_at, session.heartbeat_at)
+                END AS ended_at
+            FROM session INNER JOIN session_root ON session_root.session_id = session.id
+            WHERE session_root.root = ?1
+        )
+        SELECT strftime('%Y-%m-%d %H:%M:%S', started_at), strftime('%Y-%m-%d %H:%M:%S', ended_at),
+            strftime('%s', started_at), strftime('%s', ended_at), status
+        FROM spans ORDER BY started_at, id"
+    )?;
+    let rows = stmt.query_map(params![root.to_string_lossy(), HEARTBEAT_TIMEOUT_SECONDS], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?.parse::<i64>().unwrap_or(0),
+            row.get::<_, String>(3)?.parse::<i64>().unwrap_or(0),
+            row.get::<_, String>(4)?
+        ))
+    })?;
+
+    let mut spans: Vec<Span> = Vec::new();
+    // End of the monitored time so far, as text and in seconds.
+    let mut covered_until: Option<(String, i64)> = None;
+    let mut first_start: Option<i64> = None;
+
+    for row in rows {
+        let (start, end, start_seconds, end_seconds, status) = row?;
+        first_start.get_or_insert(start_seconds);
+
+        if let Some((until, until_seconds)) = &covered_until {
+            if start_seconds > *until_seconds {
+                spans.push(Span {
+                    start: until.clone(),
+                    end: start.clone(),
+                    seconds: start_seconds - until_seconds,
+                    status: String::from("GAP")
+                });
+            }
+        }
+
+        if covered_until.as_ref().is_none_or(|(_, until_seconds)| end_seconds > *until_seconds) {
+            covered_until = Some((end.clone(), end_seconds));
+        }
+        spans.push(Span {
+            start,
+            end,
+            seconds: end_seconds - start_seconds,
+            status
+        });
+    }
+
+    let (now, now_seconds): (String, String) = conn.query_row(
+        "SELECT strftime('%Y-%m-%d %H:%M:%S', 'now'), strftime('%s', 'now')",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?))
+    )?;
+    let now_seconds = now_seconds.parse::<i64>().unwrap_or(0);
+
+    // Not watched since the last session ended.
+    if let Some((until, until_seconds)) = covered_until {
+        if now_seconds > until_seconds {
+            spans.push(Span {
+                start: until,
+                end: now,
+                seconds: now_seconds - until_seconds,
+                status: String::from("GAP")
+            });
+        }
+    }
+
+    let gap_seconds = spans.iter()
+        .filter(|span| span.status == "GAP")
+        .map(|span| span.seconds)
+        .sum();
+
+    Ok(Coverage {
+        spans,
+        total_seconds: first_start.map_or(0, |start| now_seconds - start),
+        gap_seconds
+    })
+}
+
+pub fn format_duration(seconds: i64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds % 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use crate::testing;
+
+    fn add_session(conn: &Connection, status: &str, started_at: &str, stopped_at: Option<&str>, pid: u32, host: &str) -> i64 {
+        conn.execute(
+            "INSERT INTO session (status, started_at, heartbeat_at, stopped_at, pid, host) VALUES (?1, ?2, coalesce(?3, ?2), ?3, ?4, ?5)",
+            params![status, started_at, stopped_at, pid, host],
+        ).unwrap();
+        let id = conn.last_insert_rowid();
+        conn.execute("INSERT INTO session_root (session_id, root) VALUES (?1, '/srv')", params![id]).unwrap();
+        id
+    }
+
+    fn status(conn: &Connection, id: i64) -> String {
+        conn.query_row("SELECT status FROM session WHERE id = ?1", params![id], |row| row.get(0)).unwrap()
+    }
+
+    // A process that has already exited.
+    fn dead_pid() -> u32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        pid
+    }
+
+    #[test]
+    fn coverage_shows_the_gaps_between_sessions() {
+        let conn = testing::database();
+        let host = sink::hostname();
+        add_session(&conn, "STOPPED", "2024-01-01 10:00:00", Some("2024-01-01 11:00:00"), 1, &host);
+        add_session(&conn, "CRASHED", "2024-01-01 10:30:00", Some("2024-01-01 11:30:00"), 1, &host);
+        add_session(&conn, "STOPPED", "2024-01-01 12:00:00", Some("2024-01-01 13:00:00"), 1, &host);
+
+        let coverage = coverage(&conn, Path::new("/srv")).unwrap();
+
+        let spans: Vec<(&str, &str, i64)> = coverage.spans.iter()
+            .map(|span| (span.status.as_str(), span.start.as_str(), span.seconds))
+            .collect();
+        assert_eq!(spans[..4], [
+            ("STOPPED", "2024-01-01 10:00:00", 3600),
+            ("CRASHED", "2024-01-01 10:30:00", 3600),
+            ("GAP", "2024-01-01 11:30:00", 1800),
+            ("STOPPED", "2024-01-01 12:00:00", 3600)
+        ]);
+        // And from the last stop until now.
+        assert_eq!(spans[4].0, "GAP");
+        assert_eq!(coverage.gap_seconds, 1800 + spans[4].2);
+        assert!(coverage.percent() < 100.0);
+    }
+
+    #[test]
+    fn an_unknown_root_was_never_monitored() {
+        let conn = testing::database();
+
+        let coverage = coverage(&conn, Path::new("/elsewhere")).unwrap();
+
+        assert!(coverage.spans.is_empty());
+        assert_eq!(coverage.percent(), 100.0);
+    }
+
+    #[test]
+    fn a_silent_session_of_a_live_process_is_not_crashed() {
+        let conn = testing::database();
+        let host = sink::hostname();
+        let live = add_session(&conn, "RUNNING", "2024-01-01 10:00:00", None, process::id(), &host);
+        let dead = add_session(&conn, "RUNNING", "2024-01-01 10:00:00", None, dead_pid(), &host);
+
+        mark_crashed(&conn).unwrap();
+
+        assert_eq!(status(&conn, live), "RUNNING");
+        assert_eq!(status(&conn, dead), "CRASHED");
+    }
+
+    #[test]
+    fn sessions_of_another_host_crash_when_silent() {
+        let conn = testing::database();
+        let silent = add_session(&conn, "RUNNING", "2024-01-01 10:00:00", None, process::id(), "elsewhere");
+        let recent = add_session(&conn, "RUNNING", "2024-01-01 10:00:00", None, process::id(), "elsewhere");
+        conn.execute("UPDATE session SET heartbeat_at = CURRENT_TIMESTAMP WHERE id = ?1", params![recent]).unwrap();
+
+        mark_crashed(&conn).unwrap();
+
+        assert_eq!(status(&conn, silent), "CRASHED");
+        assert_eq!(status(&conn, recent), "RUNNING");
+    }
+
+    #[test]
+    fn beat_only_writes_once_the_interval_has_passed() {
+        let conn = testing::database();
+        let id = start(&conn, &[]).unwrap();
+        conn.execute("UPDATE session SET heartbeat_at = '2024-01-01 00:00:00' WHERE id = ?1", params![id]).unwrap();
+        let heartbeat_at = || -> String {
+            conn.query_row("SELECT heartbeat_at FROM session WHERE id = ?1", params![id], |row| row.get(0)).unwrap()
+        };
+
+        beat(&conn).unwrap();
+        assert_eq!(heartbeat_at(), "2024-01-01 00:00:00");
+
+        CURRENT.set(Some((id, Instant::now() - HEARTBEAT_INTERVAL)));
+        beat(&conn).unwrap();
+        assert_ne!(heartbeat_at(), "2024-01-01 00:00:00");
+
+        stop(&conn, id, "STOPPED").unwrap();
+        assert!(!beat_due());
+    }
+}
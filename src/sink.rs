@@ -0,0 +1,178 @@
+use std::sync::OnceLock;
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use nix::unistd::gethostname;
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::config::{Config, WebhookConfig};
+use crate::event_log::EventLog;
+use crate::live;
+use crate::metadata::format_time;
+use crate::syslog::{Syslog, SyslogQueue};
+use crate::webhook;
+
+
+
+// How long a command about to exit waits for its messages to be sent.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const EVENT_TYPES: &[&str] = &[
+    "CREATE", "DELETE", "MODIFY", "RENAME", "MOVED_OUT", "MOVED_IN", "OVERFLOW", "ATTRIB", "RESTORE",
+    "OFFLINE_CREATE", "OFFLINE_DELETE", "OFFLINE_MODIFY"
+];
+
+// What every output receives for an event, whatever its format.
+#[derive(Debug, Clone, Serialize)]
+pub struct EventRecord {
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub type_event: String,
+    pub path: String,
+    pub from_path: Option<String>,
+    pub old_sha256: Option<String>,
+    pub new_sha256: Option<String>,
+    pub old_blake3: Option<String>,
+    pub new_blake3: Option<String>,
+    pub size: Option<u64>,
+    pub mode: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub operator: Option<String>,
+    pub timestamp: String,
+    pub host: String
+}
+
+pub struct EventFilter {
+    types: Vec<String>,
+    paths: Option<GlobSet>
+}
+
+impl EventFilter {
+    pub fn new(types: &[String], paths: &[String]) -> Result<Self, Box<dyn Error>> {
+        for type_event in types {
+            if !EVENT_TYPES.contains(&type_event.as_str()) {
+                return Err(format!("unknown event type \"{}\"", type_event).into());
+            }
+        }
+
+        let paths = if paths.is_empty() {
+            None
+        } else {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in paths {
+                builder.add(Glob::new(pattern).map_err(|e| format!("invalid glob \"{}\": {}", pattern, e))?);
+            }
+            Some(builder.build()?)
+        };
+
+        Ok(Self {
+            types: types.to_vec(),
+            paths
+        })
+    }
+
+    pub fn matches(&self, record: &EventRecord) -> bool {
+        if !self.types.is_empty() && !self.types.contains(&record.type_event) {
+            return false;
+        }
+        match &self.paths {
+            Some(paths) => paths.is_match(&record.path) || record.from_path.as_ref().is_some_and(|from| paths.is_match(from)),
+            None => true
+        }
+    }
+}
+
+struct Sinks {
+    event_log: Option<EventLog>,
+    webhook: Option<(WebhookConfig, EventFilter)>,
+    syslog: Option<(SyslogQueue, EventFilter)>
+}
+
+static SINKS: OnceLock<Sinks> = OnceLock::new();
+
+// Set once at startup from [storage.event_log] and the [alerting] section;
+// before that, or without them, events only go to the database.
+pub fn init(config: &Config) -> Result<(), Box<dyn Error>> {
+    let event_log = config.storage.event_log.as_ref().map(EventLog::new);
+
+    let webhook = match &config.alerting.webhook {
+        Some(webhook) => Some((webhook.clone(), EventFilter::new(&webhook.types, &webhook.paths)?)),
+        None => None
+    };
+
+    let syslog = match &config.alerting.syslog {
+        Some(syslog) => Some((SyslogQueue::new(Syslog::new(syslog)?), EventFilter::new(&syslog.types, &syslog.paths)?)),
+        None => None
+    };
+
+    let _ = SINKS.set(Sinks { event_log, webhook, syslog });
+
+    Ok(())
+}
+
+pub fn webhook_config() -> Option<&'static WebhookConfig> {
+    SINKS.get()?.webhook.as_ref().map(|(config, _)| config)
+}
+
+// Called for every event right after it is inserted, with the same
+// connection.
+pub fn emit(
+    conn: &Connection,
+    record: &EventRecord
+) -> Result<(), Box<dyn Error>> {
+
+    live::publish(record);
+
+    let sinks = match SINKS.get() {
+        Some(sinks) => sinks,
+        None => return Ok(())
+    };
+
+    if let Some(event_log) = &sinks.event_log {
+        if let Err(e) = event_log.write(record) {
+            println!("Journal des événements : événement {} non écrit : {}", record.id, e);
+        }
+    }
+
+    if let Some((_, filter)) = &sinks.webhook {
+        if filter.matches(record) {
+            webhook::enqueue(conn, record)?;
+        }
+    }
+
+    if let Some((syslog, filter)) = &sinks.syslog {
+        if filter.matches(record) {
+            syslog.push(record);
+        }
+    }
+
+    Ok(())
+}
+
+// Before exiting, so that the messages still queued are not lost.
+pub fn flush() {
+    if let Some((syslog, _)) = SINKS.get().and_then(|sinks| sinks.syslog.as_ref()) {
+        if !syslog.flush(FLUSH_TIMEOUT) {
+            println!("Syslog : événements non envoyés avant l'arrêt");
+        }
+    }
+}
+
+pub fn now() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as i64)
+        .unwrap_or(0);
+
+    format!("{}Z", format_time(nanos).replace(' ', "T"))
+}
+
+pub fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    match gethostname(&mut buffer) {
+        Ok(name) => name.to_string_lossy().into_owned(),
+        Err(_) => String::from("unknown")
+    }
+}
@@ -0,0 +1,508 @@
+use std::error::Error;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{SyslogConfig, SyslogFormat, SyslogTransport};
+use crate::sink::EventRecord;
+
+
+
+const VENDOR: &str = "fileintegritymonitoring";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Structured data id, under the example enterprise number of RFC 5424.
+const SD_ID: &str = "fim@32473";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Messages waiting for the syslog thread; past this they are dropped rather
+// than holding up the monitor.
+const QUEUE_SIZE: usize = 1024;
+
+const FLUSH_POLL: Duration = Duration::from_millis(20);
+
+const FACILITIES: &[&str] = &[
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv", "ftp",
+    "ntp", "security", "console", "solaris-cron",
+    "local0", "local1", "local2", "local3", "local4", "local5", "local6", "local7"
+];
+
+const MONTHS: &[&str] = &["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+enum Socket {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Unix(UnixDatagram)
+}
+
+pub struct Syslog {
+    config: SyslogConfig,
+    facility: u8,
+    socket: Mutex<Option<Socket>>
+}
+
+// Sending happens on a thread of its own: a collector that is slow or
+// unreachable delays the messages, or loses them, but never the events.
+pub struct SyslogQueue {
+    sender: SyncSender<EventRecord>,
+    // Queued and not yet sent or given up.
+    pending: Arc<AtomicUsize>,
+    // Dropped because the queue was full, since the last report.
+    dropped: Arc<AtomicU64>
+}
+
+pub fn facility_code(name: &str) -> Option<u8> {
+    FACILITIES.iter().position(|facility| *facility == name).map(|code| code as u8)
+}
+
+// Syslog severity: 3 err, 4 warning, 5 notice.
+fn severity(type_event: &str) -> u8 {
+    match type_event {
+        "OVERFLOW" => 3,
+        "DELETE" | "MODIFY" | "ATTRIB" | "MOVED_OUT" | "OFFLINE_DELETE" | "OFFLINE_MODIFY" => 4,
+        _ => 5
+    }
+}
+
+// CEF and LEEF count severity from 0 to 10.
+fn severity_score(type_event: &str) -> u8 {
+    match severity(type_event) {
+        3 => 8,
+        4 => 6,
+        _ => 3
+    }
+}
+
+fn name(type_event: &str) -> &'static str {
+    match type_event {
+        "CREATE" => "File created",
+        "DELETE" => "File deleted",
+        "MODIFY" => "File modified",
+        "RENAME" => "File renamed",
+        "MOVED_OUT" => "File moved out of the monitored tree",
+        "MOVED_IN" => "File moved into the monitored tree",
+        "OVERFLOW" => "Event queue overflow",
+        "ATTRIB" => "File attributes changed",
+        "RESTORE" => "File restored",
+        "OFFLINE_CREATE" => "File created while not monitored",
+        "OFFLINE_DELETE" => "File deleted while not monitored",
+        "OFFLINE_MODIFY" => "File modified while not monitored",
+        _ => "File event"
+    }
+}
+
+// RFC 5424 allows at most six digits of fractional seconds.
+fn rfc5424_time(timestamp: &str) -> String {
+    match timestamp.find('.') {
+        Some(dot) => format!("{}Z", &timestamp[..(dot + 7).min(timestamp.len() - 1)]),
+        None => timestamp.to_string()
+    }
+}
+
+// "Oct 18 2026 14:02:11.123 UTC", understood by both CEF and LEEF.
+fn cef_time(timestamp: &str) -> String {
+    let month = timestamp.get(5..7)
+        .and_then(|month| month.parse::<usize>().ok())
+        .and_then(|month| MONTHS.get(month.wrapping_sub(1)))
+        .unwrap_or(&"Jan");
+
+    format!(
+        "{} {} {} {} UTC",
+        month,
+        timestamp.get(8..10).unwrap_or("01"),
+        timestamp.get(0..4).unwrap_or("1970"),
+        timestamp.get(11..23).unwrap_or("00:00:00.000")
+    )
+}
+
+fn header_field(value: &str, max: usize) -> String {
+    let value: String = value.chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+
+    if value.is_empty() { String::from("-") } else { value }
+}
+
+fn escape_sd(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+fn escape_cef_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn escape_cef(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('=', "\\=").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn escape_leef(value: &str) -> String {
+    value.replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+// Every field of the record that is known, as key and value.
+fn fields(record: &EventRecord) -> Vec<(&'static str, String)> {
+    let mut fields = vec![
+        ("id", record.id.to_string()),
+        ("type", record.type_event.clone()),
+        ("path", record.path.clone())
+    ];
+    let optional = [
+        ("from_path", record.from_path.clone()),
+        ("old_sha256", record.old_sha256.clone()),
+        ("new_sha256", record.new_sha256.clone()),
+        ("old_blake3", record.old_blake3.clone()),
+        ("new_blake3", record.new_blake3.clone()),
+        ("size", record.size.map(|size| size.to_string())),
+        ("mode", record.mode.clone()),
+        ("uid", record.uid.map(|uid| uid.to_string())),
+        ("gid", record.gid.map(|gid| gid.to_string())),
+        ("operator", record.operator.clone())
+    ];
+    for (key, value) in optional {
+        if let Some(value) = value {
+            fields.push((key, value));
+        }
+    }
+
+    fields
+}
+
+fn format_cef(record: &EventRecord) -> String {
+    let mut extension = vec![
+        ("externalId", record.id.to_string()),
+        ("rt", cef_time(&record.timestamp)),
+        ("act", record.type_event.clone()),
+        ("filePath", record.path.clone()),
+        ("fname", Path::new(&record.path).file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned())),
+        ("dvchost", record.host.clone())
+    ];
+    let optional = [
+        ("oldFilePath", record.from_path.clone()),
+        ("oldFileHash", record.old_sha256.clone()),
+        ("fileHash", record.new_sha256.clone()),
+        ("fsize", record.size.map(|size| size.to_string())),
+        ("filePermission", record.mode.clone()),
+        ("suser", record.operator.clone())
+    ];
+    for (key, value) in optional {
+        if let Some(value) = value {
+            extension.push((key, value));
+        }
+    }
+
+    format!(
+        "CEF:0|{}|{}|{}|{}|{}|{}|{}",
+        VENDOR,
+        VENDOR,
+        VERSION,
+        escape_cef_header(&record.type_event),
+        name(&record.type_event),
+        severity_score(&record.type_event),
+        extension.iter().map(|(key, value)| format!("{}={}", key, escape_cef(value))).collect::<Vec<_>>().join(" ")
+    )
+}
+
+fn format_leef(record: &EventRecord) -> String {
+    let mut attributes = vec![
+        ("cat", record.type_event.clone()),
+        ("sev", severity_score(&record.type_event).to_string()),
+        ("devTime", cef_time(&record.timestamp)),
+        ("devTimeFormat", String::from("MMM dd yyyy HH:mm:ss.SSS zzz")),
+        ("identHostName", record.host.clone())
+    ];
+    attributes.extend(fields(record).into_iter().map(|(key, value)| {
+        let key = match key {
+            "operator" => "usrName",
+            "type" => "eventType",
+            key => key
+        };
+        (key, value)
+    }));
+
+    format!(
+        "LEEF:1.0|{}|{}|{}|{}|{}",
+        VENDOR,
+        VENDOR,
+        VERSION,
+        record.type_event,
+        attributes.iter().map(|(key, value)| format!("{}={}", key, escape_leef(value))).collect::<Vec<_>>().join("\t")
+    )
+}
+
+impl Syslog {
+    pub fn new(config: &SyslogConfig) -> Result<Self, Box<dyn Error>> {
+        let facility = facility_code(&config.facility)
+            .ok_or_else(|| format!("unknown syslog facility \"{}\"", config.facility))?;
+
+        Ok(Self {
+            config: config.clone(),
+            facility,
+            socket: Mutex::new(None)
+        })
+    }
+
+    // One RFC 5424 line; the event is in the structured data, or in the
+    // message as CEF or LEEF.
+    fn format(&self, record: &EventRecord) -> String {
+        let (structured_data, message) = match self.config.format {
+            SyslogFormat::Rfc5424 => {
+                let params = fields(record).iter()
+                    .map(|(key, value)| format!(" {}=\"{}\"", key, escape_sd(value)))
+                    .collect::<String>();
+                (format!("[{}{}]", SD_ID, params), format!("{} {}", record.type_event, record.path))
+            }
+            SyslogFormat::Cef => (String::from("-"), format_cef(record)),
+            SyslogFormat::Leef => (String::from("-"), format_leef(record))
+        };
+
+        format!(
+            "<{}>1 {} {} {} {} {} {} {}",
+            self.facility as u32 * 8 + severity(&record.type_event) as u32,
+            rfc5424_time(&record.timestamp),
+            header_field(&record.host, 255),
+            header_field(&self.config.app_name, 48),
+            process::id(),
+            header_field(&record.type_event, 32),
+            structured_data,
+            message
+        )
+    }
+
+    fn connect(&self) -> Result<Socket, Box<dyn Error>> {
+        let address = &self.config.address;
+
+        match self.config.transport {
+            SyslogTransport::Unix => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(address)?;
+                Ok(Socket::Unix(socket))
+            }
+            SyslogTransport::Udp | SyslogTransport::Tcp => {
+                let addr = address.to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| format!("{} does not resolve", address))?;
+                if self.config.transport == SyslogTransport::Udp {
+                    let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+                    socket.connect(addr)?;
+                    Ok(Socket::Udp(socket))
+                } else {
+                    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+                    stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+                    Ok(Socket::Tcp(stream))
+                }
+            }
+        }
+    }
+
+    fn write(socket: &mut Socket, message: &str) -> Result<(), Box<dyn Error>> {
+        match socket {
+            Socket::Udp(socket) => { socket.send(message.as_bytes())?; }
+            Socket::Unix(socket) => { socket.send(message.as_bytes())?; }
+            // Octet counting framing (RFC 6587).
+            Socket::Tcp(stream) => stream.write_all(format!("{} {}", message.len(), message).as_bytes())?
+        }
+
+        Ok(())
+    }
+
+    // A dropped connection is opened again once before giving up on the
+    // message.
+    pub fn send(&self, record: &EventRecord) -> Result<(), Box<dyn Error>> {
+        let message = self.format(record);
+        let mut socket = self.socket.lock().map_err(|e| e.to_string())?;
+
+        for attempt in 0..2 {
+            if socket.is_none() {
+                *socket = Some(self.connect()?);
+            }
+            match socket.as_mut().map(|socket| Self::write(socket, &message)) {
+                Some(Err(e)) => {
+                    *socket = None;
+                    if attempt == 1 {
+                        return Err(e);
+                    }
+                }
+                _ => return Ok(())
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SyslogQueue {
+    pub fn new(syslog: Syslog) -> Self {
+        Self::with_sender(QUEUE_SIZE, move |record| syslog.send(record))
+    }
+
+    fn with_sender(
+        capacity: usize,
+        send: impl Fn(&EventRecord) -> Result<(), Box<dyn Error>> + Send + 'static
+    ) -> Self {
+
+        let (sender, receiver) = mpsc::sync_channel::<EventRecord>(capacity);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let (thread_pending, thread_dropped) = (Arc::clone(&pending), Arc::clone(&dropped));
+        thread::spawn(move || {
+            for record in receiver {
+                if let Err(e) = send(&record) {
+                    println!("Syslog : événement {} non envoyé : {}", record.id, e);
+                }
+                thread_pending.fetch_sub(1, Ordering::SeqCst);
+
+                let count = thread_dropped.swap(0, Ordering::SeqCst);
+                if count > 0 {
+                    println!("Syslog : {} événements non envoyés, file d'attente pleine", count);
+                }
+            }
+        });
+
+        Self { sender, pending, dropped }
+    }
+
+    pub fn push(&self, record: &EventRecord) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        match self.sender.try_send(record.clone()) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                self.dropped.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    // Waits for the queue to be sent, for commands about to exit. Returns
+    // false when time ran out first.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.pending.load(Ordering::SeqCst) > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(FLUSH_POLL);
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Receiver;
+    use crate::testing;
+
+    fn syslog(format: SyslogFormat, address: &str) -> Syslog {
+        Syslog::new(&SyslogConfig {
+            transport: SyslogTransport::Udp,
+            address: address.to_string(),
+            format,
+            facility: String::from("auth"),
+            app_name: String::from("fim"),
+            types: Vec::new(),
+            paths: Vec::new()
+        }).unwrap()
+    }
+
+    // A path with every character some format has to escape.
+    fn awkward_record() -> EventRecord {
+        EventRecord {
+            path: String::from("/srv/a\"b]c\\d|e=f\tg\nh"),
+            ..testing::record()
+        }
+    }
+
+    #[test]
+    fn rfc5424_header_and_structured_data() {
+        let message = syslog(SyslogFormat::Rfc5424, "127.0.0.1:514").format(&awkward_record());
+
+        // auth (4) * 8 + warning (4).
+        assert!(message.starts_with(&format!("<36>1 2026-10-18T14:02:11.123456Z web1 fim {} MODIFY [fim@32473 ", process::id())));
+        assert!(message.contains(r#" id="42" type="MODIFY" path="/srv/a\"b\]c\\d|e=f"#));
+        assert!(message.contains(r#" mode="0644" uid="33" gid="33"]"#));
+    }
+
+    #[test]
+    fn cef_escapes_the_header_and_the_extension() {
+        let record = EventRecord { type_event: String::from("A|B"), ..awkward_record() };
+        let message = syslog(SyslogFormat::Cef, "127.0.0.1:514").format(&record);
+
+        let cef = &message[message.find("CEF:").unwrap()..];
+        assert!(cef.starts_with(&format!("CEF:0|fileintegritymonitoring|fileintegritymonitoring|{}|A\\|B|File event|3|", VERSION)));
+        assert!(cef.contains(r#"filePath=/srv/a"b]c\\d|e\=f"#));
+        assert!(cef.contains(r"g\nh"));
+        assert!(cef.contains("rt=Oct 18 2026 14:02:11.123 UTC"));
+        assert!(!cef.contains('\n'));
+    }
+
+    #[test]
+    fn leef_separates_attributes_with_tabs() {
+        let message = syslog(SyslogFormat::Leef, "127.0.0.1:514").format(&awkward_record());
+
+        let leef = &message[message.find("LEEF:").unwrap()..];
+        let attributes: Vec<&str> = leef.splitn(6, '|').nth(5).unwrap().split('\t').collect();
+        assert!(attributes.contains(&"cat=MODIFY"));
+        assert!(attributes.contains(&"sev=6"));
+        assert!(attributes.contains(&r#"path=/srv/a"b]c\d|e=f\tg\nh"#));
+        assert!(!leef.contains('\n'));
+    }
+
+    #[test]
+    fn messages_reach_the_collector() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let address = collector.local_addr().unwrap().to_string();
+        let queue = SyslogQueue::new(syslog(SyslogFormat::Rfc5424, &address));
+
+        queue.push(&testing::record());
+
+        let mut buffer = [0; 4096];
+        let length = collector.recv(&mut buffer).unwrap();
+        let message = String::from_utf8_lossy(&buffer[..length]);
+        assert!(message.ends_with("MODIFY /srv/www/index.php"));
+        assert!(queue.flush(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn a_stuck_collector_drops_messages_instead_of_blocking() {
+        let (release, gate) = mpsc::channel::<()>();
+        let gate = Mutex::new(gate);
+        let (sent, sent_ids) = mpsc::channel();
+        let queue = SyslogQueue::with_sender(2, move |record| {
+            let gate: &Receiver<()> = &gate.lock().unwrap();
+            gate.recv()?;
+            sent.send(record.id)?;
+            Ok(())
+        });
+
+        let start = Instant::now();
+        for id in 0..10 {
+            queue.push(&EventRecord { id, ..testing::record() });
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(queue.dropped.load(Ordering::SeqCst) >= 7);
+        assert!(!queue.flush(Duration::from_millis(50)));
+
+        for _ in 0..10 {
+            let _ = release.send(());
+        }
+        assert!(queue.flush(Duration::from_secs(5)));
+        let ids: Vec<i64> = sent_ids.try_iter().collect();
+        assert!(ids.len() <= 3 && ids.len() >= 2);
+        assert_eq!(ids[0], 0);
+    }
+}
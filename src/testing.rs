@@ -0,0 +1,46 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use rusqlite::Connection;
+
+use crate::app::AppFIM;
+use crate::sink::EventRecord;
+
+
+
+// An empty database with the current schema.
+pub fn database() -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    AppFIM::migrate(&conn).unwrap();
+    conn
+}
+
+// A fresh directory per test, so that tests running in parallel never see
+// each other's files.
+pub fn directory(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fim-test-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// What the sinks receive for a modified file.
+pub fn record() -> EventRecord {
+    EventRecord {
+        id: 42,
+        type_event: String::from("MODIFY"),
+        path: String::from("/srv/www/index.php"),
+        from_path: None,
+        old_sha256: Some("a".repeat(64)),
+        new_sha256: Some("b".repeat(64)),
+        old_blake3: None,
+        new_blake3: None,
+        size: Some(120),
+        mode: Some(String::from("0644")),
+        uid: Some(33),
+        gid: Some(33),
+        operator: None,
+        timestamp: String::from("2026-10-18T14:02:11.123456789Z"),
+        host: String::from("web1")
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+
+use crate::sink::EVENT_TYPES;
+
+
+
+// Levels of the tree shown unfolded when the page opens.
+const OPEN_DEPTH: usize = 2;
+
+#[derive(Default)]
+struct Node {
+    // Events on this path itself, by type.
+    own: BTreeMap<String, i64>,
+    // Events on this path and everything below it, by type.
+    counts: BTreeMap<String, i64>,
+    children: BTreeMap<String, Node>
+}
+
+// A line of the tree, or the end of a directory when `closing` is set.
+pub struct TreeRow {
+    pub name: String,
+    pub path: String,
+    pub total: i64,
+    pub counts: Vec<(String, i64)>,
+    pub has_children: bool,
+    pub open: bool,
+    pub closing: bool
+}
+
+impl Node {
+    fn add(&mut self, type_event: &str, count: i64) {
+        *self.counts.entry(type_event.to_string()).or_default() += count;
+    }
+
+    fn total(&self) -> i64 {
+        self.counts.values().sum()
+    }
+
+    // A directory whose only content is one other directory is shown as a
+    // single line, "/srv/www" rather than "/" then "srv" then "www".
+    fn merge_chains(&mut self, name: &mut String) {
+        while self.own.is_empty() && self.children.len() == 1 {
+            let (child_name, child) = match self.children.pop_first() {
+                Some(child) => child,
+                None => break
+            };
+            if !name.ends_with('/') {
+                name.push('/');
+            }
+            name.push_str(&child_name);
+            *self = child;
+        }
+    }
+}
+
+// Known types first, in their usual order.
+fn sorted_counts(counts: &BTreeMap<String, i64>) -> Vec<(String, i64)> {
+    let mut sorted: Vec<(String, i64)> = counts.iter().map(|(type_event, count)| (type_event.clone(), *count)).collect();
+    sorted.sort_by_key(|(type_event, _)| EVENT_TYPES.iter().position(|known| known == type_event).unwrap_or(EVENT_TYPES.len()));
+    sorted
+}
+
+fn flatten(
+    node: &mut Node,
+    mut name: String,
+    path: String,
+    depth: usize,
+    rows: &mut Vec<TreeRow>
+) {
+
+    node.merge_chains(&mut name);
+    let path = match depth {
+        0 => name.clone(),
+        _ if path.ends_with('/') => format!("{}{}", path, name),
+        _ => format!("{}/{}", path, name)
+    };
+
+    rows.push(TreeRow {
+        name,
+        path: path.clone(),
+        total: node.total(),
+        counts: sorted_counts(&node.counts),
+        has_children: !node.children.is_empty(),
+        open: depth < OPEN_DEPTH,
+        closing: false
+    });
+    if node.children.is_empty() {
+        return;
+    }
+
+    // The busiest first, which is what needs looking at.
+    let mut children: Vec<(String, Node)> = std::mem::take(&mut node.children).into_iter().collect();
+    children.sort_by(|(a_name, a), (b_name, b)| b.total().cmp(&a.total()).then_with(|| a_name.cmp(b_name)));
+    for (child_name, mut child) in children {
+        flatten(&mut child, child_name, path.clone(), depth + 1, rows);
+    }
+
+    rows.push(TreeRow {
+        name: String::new(),
+        path: String::new(),
+        total: 0,
+        counts: Vec::new(),
+        has_children: false,
+        open: false,
+        closing: true
+    });
+}
+
+// From the number of events per file and type, the counts of every
+// directory above them, in display order.
+pub fn rollup(
+    counts: &[(String, String, i64)]
+) -> Vec<TreeRow> {
+
+    let mut root = Node::default();
+    for (file_path, type_event, count) in counts {
+        let mut node = &mut root;
+        node.add(type_event, *count);
+        for component in file_path.split('/').filter(|component| !component.is_empty()) {
+            node = node.children.entry(component.to_string()).or_default();
+            node.add(type_event, *count);
+        }
+        *node.own.entry(type_event.clone()).or_default() += count;
+    }
+
+    let mut rows = Vec::new();
+    if root.total() > 0 {
+        flatten(&mut root, String::from("/"), String::new(), 0, &mut rows);
+    }
+
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rollup_of(counts: &[(&str, &str, i64)]) -> Vec<TreeRow> {
+        let counts: Vec<(String, String, i64)> = counts.iter()
+            .map(|(path, type_event, count)| (path.to_string(), type_event.to_string(), *count))
+            .collect();
+        rollup(&counts)
+    }
+
+    // Name, path and total of each line, "end" for the closing ones.
+    fn lines(rows: &[TreeRow]) -> Vec<(String, String, i64)> {
+        rows.iter()
+            .map(|row| match row.closing {
+                true => (String::from("end"), String::new(), 0),
+                false => (row.name.clone(), row.path.clone(), row.total)
+            })
+            .collect()
+    }
+
+    fn line(name: &str, path: &str, total: i64) -> (String, String, i64) {
+        (name.to_string(), path.to_string(), total)
+    }
+
+    #[test]
+    fn chains_are_merged_and_the_busiest_come_first() {
+        let rows = rollup_of(&[
+            ("/srv/www/a.php", "MODIFY", 3),
+            ("/srv/www/b.php", "MODIFY", 1),
+            ("/srv/www/b.php", "CREATE", 1),
+            ("/srv/www/lib/c.php", "DELETE", 5),
+            ("/etc/passwd", "MODIFY", 2)
+        ]);
+
+        assert_eq!(lines(&rows), [
+            line("/", "/", 12),
+            line("srv/www", "/srv/www", 10),
+            line("lib/c.php", "/srv/www/lib/c.php", 5),
+            line("a.php", "/srv/www/a.php", 3),
+            line("b.php", "/srv/www/b.php", 2),
+            line("end", "", 0),
+            line("etc/passwd", "/etc/passwd", 2),
+            line("end", "", 0)
+        ]);
+        assert_eq!(rows[0].counts, [(String::from("CREATE"), 1), (String::from("DELETE"), 5), (String::from("MODIFY"), 6)]);
+        assert_eq!(rows[4].counts, [(String::from("CREATE"), 1), (String::from("MODIFY"), 1)]);
+        assert!(rows[0].has_children && rows[0].open);
+        assert!(rows[1].has_children && rows[1].open);
+        assert!(!rows[2].has_children && !rows[2].open);
+    }
+
+    #[test]
+    fn a_directory_with_its_own_events_stays_a_line() {
+        let rows = rollup_of(&[
+            ("/srv", "OVERFLOW", 1),
+            ("/srv/www/a", "MODIFY", 1),
+            ("/srv/www/a", "ACCESS", 1)
+        ]);
+
+        assert_eq!(lines(&rows), [
+            line("/srv", "/srv", 3),
+            line("www/a", "/srv/www/a", 2),
+            line("end", "", 0)
+        ]);
+        // Types the tree does not know come last.
+        assert_eq!(rows[1].counts, [(String::from("MODIFY"), 1), (String::from("ACCESS"), 1)]);
+    }
+
+    #[test]
+    fn nothing_to_show() {
+        assert!(rollup_of(&[]).is_empty());
+        assert!(rollup_of(&[("/srv/a", "MODIFY", 0)]).is_empty());
+    }
+}
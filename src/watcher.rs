@@ -4,6 +4,8 @@ use inotify::{Inotify, WatchDescriptor, WatchMask};
 use std::error::Error;
 use std::collections::HashMap;
 
+use crate::filter::Filters;
+
 
 
 fn add_dir_watch(
@@ -16,7 +18,7 @@ fn add_dir_watch(
         .watches()
         .add(
             dir,
-            WatchMask::MODIFY | WatchMask::DELETE | WatchMask::CREATE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO,
+            WatchMask::MODIFY | WatchMask::ATTRIB | WatchMask::DELETE | WatchMask::CREATE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO,
         )?;
 
     watched_dirs.insert(wd, dir.to_path_buf());
@@ -28,6 +30,7 @@ pub fn watch_directory_recursive(
     inotify: &Inotify,
     dir: &Path,
     watched_dirs: &mut HashMap<WatchDescriptor, PathBuf>,
+    filters: &Filters
 ) -> Result<(), Box<dyn Error>> {
 
     let dir_metadata = match fs::metadata(dir) {
@@ -37,21 +40,16 @@ pub fn watch_directory_recursive(
         }
     };
 
-    if dir_metadata.is_dir() {
-        match add_dir_watch(inotify, dir, watched_dirs) {
-            Ok(_) => (),
-            Err(_) => {
-                return Ok(());
-            }
-        };
+    if dir_metadata.is_dir() && !filters.is_excluded(dir, true) {
+        if add_dir_watch(inotify, dir, watched_dirs).is_err() {
+            return Ok(());
+        }
 
         let dir_entries = fs::read_dir(dir)?;
-        for entry in dir_entries {
-            if let Ok(entry) = entry {
-                watch_directory_recursive(inotify, &entry.path(), watched_dirs)?;
-            }
+        for entry in dir_entries.flatten() {
+            watch_directory_recursive(inotify, &entry.path(), watched_dirs, filters)?;
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
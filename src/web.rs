@@ -1,158 +1,532 @@
-use actix_web::{get, web, App, HttpServer, Responder, HttpResponse};
+use actix_web::{get, post, web, App, HttpServer, HttpRequest, HttpMessage, Responder, HttpResponse};
+use actix_web::dev::Service;
+use actix_web::web::Bytes;
+use askama::Template;
+use futures_util::stream;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::fs;
-use std::collections::HashMap;
-use std::path::PathBuf;
-use rusqlite::{Connection, Result, params, Error};
-
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use rusqlite::{Connection, Result, Row, params, params_from_iter, Error};
+use tokio::sync::broadcast::{self, error::{RecvError, TryRecvError}};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::api;
+use crate::diff_view::{self, DiffHunk};
+use crate::tree::{rollup, TreeRow};
 use crate::app::AppFIM;
-use crate::path::check_path;
+use crate::auth::{self, User};
+use crate::live;
+use crate::search::{EventSearch, EVENT_TABLES};
+use crate::sink::{EventRecord, EVENT_TYPES};
+use crate::session;
+use crate::restore::restore as restore_version;
+use crate::config::RootConfig;
+use crate::session::Coverage;
+use crate::event_file::{history as path_history, version_at_event, version_before_event, version_at_time, Version};
 
 
 
 #[derive(Debug)]
 struct EventPatch {
-    diff_patch: Vec<u8>
+    diff_patch: Vec<u8>,
+    old_sha256: Option<String>,
+    new_sha256: Option<String>,
+    old_blake3: Option<String>,
+    new_blake3: Option<String>,
+    file_path: String,
+    from_path: Option<String>,
+    type_event: String,
+    date_event: String,
+    // The changes of the same file just before and just after this one.
+    previous: Option<i64>,
+    next: Option<i64>
 }
 
-fn fetch_events() -> Result<Vec<Result<(u32, String, String, String, Vec<u8>), Error>>> {
-    let conn = Connection::open("database.db")?;
-    let mut stmt = conn.prepare("SELECT event.id, path.file_path, event.type_event, strftime('%Y-%m-%d %H:%M:%S', event.date_event) as date_event, event.diff FROM event INNER JOIN path ON event.path_id = path.id ORDER BY event.date_event DESC")?;
-    let rows = stmt.query_map([], |row| {
-        Ok((
-            row.get::<_, u32>(0)?,
-            row.get::<_, String>(1)?,
-            row.get::<_, String>(2)?,
-            row.get::<_, String>(3)?,
-            row.get::<_, Vec<u8>>(4)?,
-        ))
-    })?;
-    
-    let mut events = Vec::new();
-    for row in rows {
-        events.push(row);
-    }
-    Ok(events)
+struct EventRow {
+    id: u32,
+    file_path: String,
+    from_path: Option<String>,
+    type_event: String,
+    date_event: String,
+    has_diff: bool,
+    old_sha256: Option<String>,
+    new_sha256: Option<String>
+}
+
+// Events shown on a page of the online view.
+const PAGE_SIZE: u32 = 100;
+
+const EVENT_COLUMNS: &str = "event.id, path.file_path, from_path.file_path, event.type_event, strftime('%Y-%m-%d %H:%M:%S', event.date_event),
+    coalesce(length(event.diff), 0) > 0, event.old_sha256, event.new_sha256";
+
+fn event_row(row: &Row) -> Result<EventRow> {
+    Ok(EventRow {
+        id: row.get(0)?,
+        file_path: row.get(1)?,
+        from_path: row.get(2)?,
+        type_event: row.get(3)?,
+        date_event: row.get(4)?,
+        has_diff: row.get(5)?,
+        old_sha256: row.get(6)?,
+        new_sha256: row.get(7)?,
+    })
+}
+
+// In i64, as any page number can be asked for.
+fn page_offset(page: u32) -> i64 {
+    (i64::from(page) - 1) * i64::from(PAGE_SIZE)
+}
+
+// A page of the events matching the search, newest first, and how many
+// match in all.
+fn fetch_events(
+    conn: &Connection,
+    search: &EventSearch,
+    page: u32
+) -> Result<(Vec<EventRow>, i64)> {
+
+    let (filter, values) = search.clause();
+    let total = conn.query_row(
+        &format!("SELECT count(*) FROM {} {}", EVENT_TABLES, filter),
+        params_from_iter(&values),
+        |row| row.get(0)
+    )?;
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM {} {} ORDER BY event.date_event DESC, event.id DESC LIMIT {} OFFSET {}",
+        EVENT_COLUMNS, EVENT_TABLES, filter, PAGE_SIZE, page_offset(page)
+    ))?;
+    let events = stmt.query_map(params_from_iter(&values), event_row)?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok((events, total))
+}
+
+// Oldest first, a batch at a time, for the live stream.
+fn fetch_events_after(
+    conn: &Connection,
+    search: &EventSearch
+) -> Result<Vec<EventRow>> {
+
+    let (filter, values) = search.clause();
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM {} {} ORDER BY event.id LIMIT 500", EVENT_COLUMNS, EVENT_TABLES, filter))?;
+    let rows = stmt.query_map(params_from_iter(&values), event_row)?;
+
+    rows.collect()
 }
 
 fn get_diff(
+    database: &Path,
     id: u32
-) -> Result<Vec<u8>, Error> {
-    let conn = Connection::open("database.db")?;
+) -> Result<EventPatch, Error> {
+    let conn = Connection::open(database)?;
     
-    let mut stmt = conn.prepare("SELECT diff FROM event WHERE id = ?1")?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT event.diff, event.old_sha256, event.new_sha256, event.old_blake3, event.new_blake3,
+            path.file_path, from_path.file_path, event.type_event, strftime('%Y-%m-%d %H:%M:%S', event.date_event),
+            (SELECT max(other.id) FROM event AS other WHERE other.path_id = event.path_id AND other.id < event.id AND length(other.diff) > 0),
+            (SELECT min(other.id) FROM event AS other WHERE other.path_id = event.path_id AND other.id > event.id AND length(other.diff) > 0)
+        FROM {} WHERE event.id = ?1",
+        EVENT_TABLES
+    ))?;
     let patch: EventPatch = stmt.query_row(params![id], |row| {
         Ok(EventPatch {
-            diff_patch: row.get(0)?
+            diff_patch: row.get::<_, Option<Vec<u8>>>(0)?.unwrap_or_default(),
+            old_sha256: row.get(1)?,
+            new_sha256: row.get(2)?,
+            old_blake3: row.get(3)?,
+            new_blake3: row.get(4)?,
+            file_path: row.get(5)?,
+            from_path: row.get(6)?,
+            type_event: row.get(7)?,
+            date_event: row.get(8)?,
+            previous: row.get(9)?,
+            next: row.get(10)?
         })
     })?;
 
-    let diff_patch = patch.diff_patch;
-    Ok(diff_patch)
+    Ok(patch)
 }
 
-#[get("/")]
-async fn index(data: web::Data<Arc<Mutex<AppFIM>>>) -> impl Responder {
-    let app_fim_mutex = data.get_ref();
-    let app_fim = app_fim_mutex.lock().unwrap();
-    let file_state;
+struct TimelineEntry {
+    id: i64,
+    // RENAMED_AWAY when the file left this path for another one.
+    type_event: String,
+    date_event: String,
+    // The path on the other side of a rename.
+    other_path: Option<String>,
+    old_sha256: Option<String>,
+    new_sha256: Option<String>,
+    operator: Option<String>,
+    // The mode and ownership changes since the previous event, such as
+    // "mode 0644 → 0600".
+    attributes: Vec<String>,
+    hunks: Vec<DiffHunk>
+}
+
+// Every event of a path, oldest first, including the renames that brought
+// the file there or took it away.
+fn fetch_timeline(
+    conn: &Connection,
+    path: &str
+) -> Result<Vec<TimelineEntry>, Box<dyn std::error::Error>> {
+
+    let mut stmt = conn.prepare(
+        "SELECT event.id, CASE WHEN event.path_id = target.id THEN event.type_event ELSE 'RENAMED_AWAY' END,
+            strftime('%Y-%m-%d %H:%M:%S', event.date_event),
+            CASE WHEN event.path_id = target.id THEN from_path.file_path ELSE path.file_path END,
+            event.old_sha256, event.new_sha256, event.operator, event.mode, event.uid, event.gid, event.diff
+        FROM path AS target INNER JOIN event ON event.path_id = target.id OR event.from_path_id = target.id
+        INNER JOIN path ON event.path_id = path.id
+        LEFT JOIN path AS from_path ON event.from_path_id = from_path.id
+        WHERE target.file_path = ?1 ORDER BY event.id"
+    )?;
+    let rows = stmt.query_map(params![path], |row| {
+        Ok((
+            TimelineEntry {
+                id: row.get(0)?,
+                type_event: row.get(1)?,
+                date_event: row.get(2)?,
+                other_path: row.get(3)?,
+                old_sha256: row.get(4)?,
+                new_sha256: row.get(5)?,
+                operator: row.get(6)?,
+                attributes: Vec::new(),
+                hunks: Vec::new()
+            },
+            [row.get::<_, Option<u32>>(7)?, row.get(8)?, row.get(9)?],
+            row.get::<_, Option<Vec<u8>>>(10)?.unwrap_or_default()
+        ))
+    })?;
+
+    let mut entries = Vec::new();
+    let mut known: [Option<u32>; 3] = [None; 3];
+    for row in rows {
+        let (mut entry, attributes, diff) = row?;
+
+        for (position, (name, value)) in ["mode", "uid", "gid"].iter().zip(attributes).enumerate() {
+            let show = |value: u32| if position == 0 { format!("{:04o}", value & 0o7777) } else { value.to_string() };
+            if let (Some(before), Some(after)) = (known[position], value) {
+                if before != after {
+                    entry.attributes.push(format!("{} {} → {}", name, show(before), show(after)));
+                }
+            }
+            if value.is_some() {
+                known[position] = value;
+            }
+        }
+        if !diff.is_empty() {
+            entry.hunks = diff_view::hunks(&diff)?;
+        }
+        entries.push(entry);
+    }
 
-    if app_fim.state == false {
-        file_state = String::from("offline");
+    Ok(entries)
+}
+
+fn database(data: &web::Data<Arc<Mutex<AppFIM>>>) -> PathBuf {
+    data.get_ref().lock().unwrap().config.storage.database.clone()
+}
+
+impl EventRow {
+    fn old_short(&self) -> String {
+        short_hash(&self.old_sha256)
+    }
+
+    fn new_short(&self) -> String {
+        short_hash(&self.new_sha256)
+    }
+}
+
+fn short_hash(hash: &Option<String>) -> String {
+    match hash {
+        Some(hash) => hash.chars().take(12).collect(),
+        None => String::from("-")
+    }
+}
+
+#[derive(Template)]
+#[template(path = "index_offline.html")]
+struct OfflinePage<'a> {
+    csrf: &'a str,
+    roots: &'a [RootConfig]
+}
+
+#[derive(Template)]
+#[template(path = "index_online.html")]
+struct OnlinePage<'a> {
+    csrf: &'a str,
+    events: Vec<EventRow>,
+    search: &'a EventSearch,
+    types: Vec<(&'static str, bool)>,
+    roots: Vec<(String, bool)>,
+    total: i64,
+    first: i64,
+    last: i64,
+    newer: Option<String>,
+    older: Option<String>,
+    // New events are pushed only to the first page.
+    live: bool,
+    query: String,
+    last_id: i64
+}
+
+#[derive(Template)]
+#[template(path = "event.html")]
+struct EventItem<'a> {
+    event: &'a EventRow
+}
+
+struct RootCoverage {
+    path: String,
+    coverage: Coverage,
+    total: String,
+    gap: String
+}
+
+#[derive(Template)]
+#[template(path = "coverage.html")]
+struct CoveragePage {
+    roots: Vec<RootCoverage>
+}
+
+#[derive(Template)]
+#[template(path = "history.html")]
+struct HistoryPage<'a> {
+    csrf: &'a str,
+    path: &'a str,
+    versions: Vec<Version>
+}
+
+#[derive(Template)]
+#[template(path = "diff.html")]
+struct DiffPage<'a> {
+    id: u32,
+    patch: &'a EventPatch,
+    hunks: Vec<DiffHunk>,
+    // Instead of hunks, the changes an ATTRIB event lists.
+    attributes: Vec<String>,
+    error: String,
+    split: bool,
+    view: &'static str
+}
+
+#[derive(Template)]
+#[template(path = "timeline.html")]
+struct TimelinePage<'a> {
+    path: &'a str,
+    entries: Vec<TimelineEntry>,
+    // For the shared diff table, always unified here.
+    split: bool
+}
+
+#[derive(Template)]
+#[template(path = "tree.html")]
+struct TreePage<'a> {
+    search: &'a EventSearch,
+    types: Vec<(&'static str, bool)>,
+    // Shortcuts to the last day, week and month.
+    presets: Vec<(&'static str, String)>,
+    rows: Vec<TreeRow>
+}
+
+impl TreePage<'_> {
+    // The event list of a directory or file, with the filters of the tree.
+    fn events_link(&self, path: &str) -> String {
+        let mut search = self.search.clone();
+        search.path = Some(path.to_string());
+        format!("/?{}", search.query_string())
+    }
+}
+
+#[derive(Template)]
+#[template(path = "login.html")]
+struct LoginPage<'a> {
+    error: &'a str
+}
+
+fn render(page: &impl Template) -> HttpResponse {
+    match page.render() {
+        Ok(html) => HttpResponse::Ok().content_type("text/html; charset=utf-8").body(html),
+        Err(_) => HttpResponse::InternalServerError().finish()
+    }
+}
+
+fn page_link(query: &str, page: u32) -> String {
+    if query.is_empty() {
+        format!("/?page={}", page)
     } else {
-        file_state = String::from("online");
+        format!("/?{}&page={}", query, page)
     }
+}
 
-    let html_content = match fs::read_to_string(format!("website/index_{}.html", file_state)) {
-        Ok(content) => content,
-        Err(_) => return HttpResponse::InternalServerError().finish()
+#[get("/")]
+async fn index(req: HttpRequest, data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Query<HashMap<String, String>>) -> impl Responder {
+    let csrf = auth::csrf_token(&req);
+    let (database, roots) = {
+        let app_fim = data.get_ref().lock().unwrap();
+        if !app_fim.state {
+            return render(&OfflinePage {
+                csrf: &csrf,
+                roots: &app_fim.config.roots
+            });
+        }
+        let roots: Vec<String> = app_fim.config.roots.iter().map(|root| root.path.to_string_lossy().into_owned()).collect();
+        (app_fim.config.storage.database.clone(), roots)
     };
-    let css_content = match fs::read_to_string(format!("website/style/style_{}.css", file_state)) {
-        Ok(content) => content,
+
+    let search = EventSearch::from_query(&info);
+    let page = info.get("page")
+        .and_then(|page| page.parse::<u32>().ok())
+        .filter(|page| *page > 0)
+        .unwrap_or(1);
+
+    let conn = match Connection::open(&database) {
+        Ok(conn) => conn,
         Err(_) => return HttpResponse::InternalServerError().finish()
     };
-    let mut html_with_css = html_content.replace("{}", &css_content);
-
-    if app_fim.state == false {
-        for item in &app_fim.path_from_web {
-            html_with_css = html_with_css.replace("<!---->", "
-            <!---->
-            <form class=\"path-item\" action=\"http://127.0.0.1:6077/del\" method=\"get\">
-                <input type=\"hidden\" name=\"path\" value=\"{}\">
-                <span class=\"path-text\">{}</span>
-                <button type=\"submit\" class=\"remove-btn\">Remove</button>
-            </form>
-            ");
-
-            html_with_css = match item.to_str() {
-                Some(path_str) => html_with_css.replace("{}", path_str),
-                None => continue
-            }
+    // Read before the page: an event inserted in between may then come
+    // twice, which the page ignores, rather than never.
+    let last_id = conn.query_row("SELECT coalesce(max(id), 0) FROM event", [], |row| row.get(0)).unwrap_or(0);
+    let (events, total) = fetch_events(&conn, &search, page).unwrap_or_default();
+
+    let query = search.query_string();
+    let first = page_offset(page);
+    let last = first + events.len() as i64;
+
+    render(&OnlinePage {
+        csrf: &csrf,
+        search: &search,
+        types: EVENT_TYPES.iter().map(|type_event| (*type_event, search.type_event.as_deref() == Some(*type_event))).collect(),
+        roots: roots.into_iter().map(|root| {
+            let selected = search.root.as_ref() == Some(&root);
+            (root, selected)
+        }).collect(),
+        total,
+        first: if events.is_empty() { first } else { first + 1 },
+        last,
+        newer: (page > 1).then(|| page_link(&query, page - 1)),
+        older: (last < total).then(|| page_link(&query, page + 1)),
+        live: page == 1,
+        query,
+        last_id,
+        events
+    })
+}
+
+// Without news for this long the stream sends a comment, which keeps
+// proxies from closing it and lets the session be checked again.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+// How long the browser waits before reconnecting, in milliseconds.
+const RETRY_MS: u32 = 3000;
+
+struct LiveStream {
+    conn: Connection,
+    receiver: broadcast::Receiver<EventRecord>,
+    session: Option<String>,
+    // The filters of the page, and the last event sent.
+    search: EventSearch,
+    pending: VecDeque<Bytes>
+}
+
+// The id lets the browser resume after a reconnection; the data is the
+// event as the page shows it.
+fn sse_message(event: &EventRow) -> Bytes {
+    let mut message = format!("id: {}\n", event.id);
+    if let Ok(html) = (EventItem { event }).render() {
+        for line in html.lines() {
+            message.push_str("data: ");
+            message.push_str(line);
+            message.push('\n');
         }
-    } else {
-        match fetch_events() {
-            Ok(events) => {
-                for event in events {
-                    match event {
-                        Ok((id, file_path, type_event, date_event, diff)) => {
-                            html_with_css = html_with_css.replace("<!---->", "
-                            <div class=\"event\">
-                                <div class=\"event-indicator indicator-{1}\">
-                                    <div class=\"event-indicator-circle\"></div>
-                                    <div class=\"event-indicator-label\">{2}</div>
-                                </div>
-                                <div class=\"event-path\">{3}</div>
-                                <div class=\"event-date\">{4}</div>
-                                {5}
-                            </div>
-                            <!---->
-                            ");
-
-                            html_with_css = match type_event.as_str() {
-                                "CREATE" => html_with_css.replace("{1}", "create"),
-                                "DELETE" => html_with_css.replace("{1}", "delete"),
-                                "MODIFY" => html_with_css.replace("{1}", "modify"),
-                                "MOVED_FROM" => html_with_css.replace("{1}", "moved_from"),
-                                "MOVED_TO" => html_with_css.replace("{1}", "moved_to"),
-                                _ => {
-                                    continue;
-                                }
-                            };
-
-                            html_with_css = html_with_css.replace("{2}", type_event.as_str());
-                            html_with_css = html_with_css.replace("{3}", file_path.as_str());
-                            html_with_css = html_with_css.replace("{4}", date_event.as_str());
-
-                            if diff.is_empty() {
-                                html_with_css = html_with_css.replace("{5}", "");
-                            } else {
-                                let tmp_link = "<a href=\"{6}\" class=\"event-link\">See more</a>";
-                                let tmp_id = format!("http://127.0.0.1:6077/diffweb?id={}", id);
-                                let tmp = tmp_link.replace("{6}", tmp_id.as_str());
-                                html_with_css = html_with_css.replace("{5}", tmp.as_str());
-                            }
-                        },
-                        _ => continue
+    }
+    message.push('\n');
+
+    Bytes::from(message)
+}
+
+// The bus only wakes the stream up: what to send is read from the database,
+// which also covers the events missed while disconnected or lagging.
+async fn next_message(mut live: LiveStream) -> Option<(Result<Bytes, actix_web::Error>, LiveStream)> {
+    loop {
+        if let Some(message) = live.pending.pop_front() {
+            return Some((Ok(message), live));
+        }
+
+        let events = fetch_events_after(&live.conn, &live.search).ok()?;
+        for event in &events {
+            live.search.after = Some(i64::from(event.id));
+            live.pending.push_back(sse_message(event));
+        }
+        if !live.pending.is_empty() {
+            continue;
+        }
+
+        match tokio::time::timeout(KEEP_ALIVE, live.receiver.recv()).await {
+            Ok(Ok(_)) | Ok(Err(RecvError::Lagged(_))) => {
+                // A burst of events needs a single read.
+                while !matches!(live.receiver.try_recv(), Err(TryRecvError::Empty | TryRecvError::Closed)) {}
+            }
+            Ok(Err(RecvError::Closed)) => return None,
+            Err(_) => {
+                if let Some(token) = &live.session {
+                    if !auth::session_active(&live.conn, token) {
+                        return None;
                     }
                 }
-            },
-            _ => ()
+                live.pending.push_back(Bytes::from_static(b": keep-alive\n\n"));
+            }
         }
     }
+}
+
+#[get("/stream")]
+async fn live_stream(req: HttpRequest, data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Query<HashMap<String, String>>) -> impl Responder {
+    // Subscribed before reading the database, so that nothing inserted in
+    // between is lost.
+    let receiver = live::subscribe();
+    let conn = match Connection::open(database(&data)) {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    // The browser sends Last-Event-ID when it reconnects; the page gives the
+    // newest event it shows.
+    let resume = req.headers().get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .or(info.get("after").map(String::as_str));
+    let last = match resume.map(str::parse::<i64>) {
+        Some(Ok(last)) => last,
+        Some(Err(_)) => return HttpResponse::BadRequest().body("invalid event id"),
+        None => match conn.query_row("SELECT COALESCE(MAX(id), 0) FROM event", [], |row| row.get(0)) {
+            Ok(last) => last,
+            Err(_) => return HttpResponse::InternalServerError().finish()
+        }
+    };
 
-    HttpResponse::Ok().body(html_with_css)
+    let mut search = EventSearch::from_query(&info);
+    search.after = Some(last);
+
+    let live = LiveStream {
+        conn,
+        receiver,
+        session: req.cookie(auth::COOKIE_NAME).map(|cookie| cookie.value().to_string()),
+        search,
+        pending: VecDeque::from([Bytes::from(format!("retry: {}\n\n", RETRY_MS))])
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .append_header(("Cache-Control", "no-cache"))
+        .append_header(("X-Accel-Buffering", "no"))
+        .streaming(stream::unfold(live, next_message))
 }
 
+// The diff as a page, or with `raw` the patch itself.
 #[get("/diffweb")]
 async fn diffweb(data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Query<HashMap<String, String>>) -> impl Responder {
-    let app_fim_mutex = data.get_ref();
-    let app_fim = app_fim_mutex.lock().unwrap();
-
-    if app_fim.state == false {
-        return HttpResponse::Found().append_header(("Location", "/")).finish();
-    }
-
     let id_str = match info.get("id") {
         Some(i) => i,
         None => return HttpResponse::Found().append_header(("Location", "/")).finish()
@@ -163,94 +537,346 @@ async fn diffweb(data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Query<HashMap<S
         Err(_) => return HttpResponse::Found().append_header(("Location", "/")).finish()
     };
 
-    let diff = match get_diff(id) {
+    let patch = match get_diff(&database(&data), id) {
         Ok(d) => d,
         Err(_) => return HttpResponse::Found().append_header(("Location", "/")).finish()
     };
 
-    HttpResponse::Ok().body(diff)
+    if info.contains_key("raw") {
+        let mut response = HttpResponse::Ok();
+        let hashes = [
+            ("X-FIM-SHA256-Before", &patch.old_sha256),
+            ("X-FIM-SHA256-After", &patch.new_sha256),
+            ("X-FIM-BLAKE3-Before", &patch.old_blake3),
+            ("X-FIM-BLAKE3-After", &patch.new_blake3)
+        ];
+        for (header, hash) in hashes {
+            if let Some(hash) = hash {
+                response.append_header((header, hash.as_str()));
+            }
+        }
+
+        return response.content_type("text/x-diff; charset=utf-8").body(patch.diff_patch);
+    }
+
+    // ATTRIB events, offline ones included, hold lines such as
+    // "mode: 0644 -> 4755" rather than a patch.
+    let attributes: Vec<String> = match patch.type_event.as_str() {
+        "ATTRIB" => String::from_utf8_lossy(&patch.diff_patch).lines().map(String::from).collect(),
+        _ => Vec::new()
+    };
+    let (hunks, error) = if !attributes.is_empty() {
+        (Vec::new(), String::new())
+    } else {
+        match diff_view::hunks(&patch.diff_patch) {
+            Ok(hunks) => (hunks, String::new()),
+            Err(e) => (Vec::new(), e.to_string())
+        }
+    };
+    let split = info.get("view").map(String::as_str) == Some("split");
+
+    render(&DiffPage {
+        id,
+        patch: &patch,
+        hunks,
+        attributes,
+        error,
+        split,
+        view: if split { "split" } else { "unified" }
+    })
 }
 
-#[get("/start")]
-async fn start(data: web::Data<Arc<Mutex<AppFIM>>>) -> impl Responder {
-    let app_fim_mutex = data.get_ref();
-    let mut app_fim = app_fim_mutex.lock().unwrap();
-    app_fim.state = true;
+#[get("/coverage")]
+async fn coverage(data: web::Data<Arc<Mutex<AppFIM>>>) -> impl Responder {
+    let (database, mut roots) = {
+        let app_fim = data.get_ref().lock().unwrap();
+        let roots: Vec<PathBuf> = app_fim.config.roots.iter().map(|root| root.path.clone()).collect();
+        (app_fim.config.storage.database.clone(), roots)
+    };
+
+    let conn = match Connection::open(&database) {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+    match session::roots(&conn) {
+        Ok(monitored) => {
+            for root in monitored {
+                if !roots.contains(&root) {
+                    roots.push(root);
+                }
+            }
+        }
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    let mut coverages = Vec::new();
+    for root in roots {
+        let coverage = match session::coverage(&conn, &root) {
+            Ok(coverage) => coverage,
+            Err(_) => return HttpResponse::InternalServerError().finish()
+        };
+        coverages.push(RootCoverage {
+            path: root.to_string_lossy().into_owned(),
+            total: session::format_duration(coverage.total_seconds),
+            gap: session::format_duration(coverage.gap_seconds),
+            coverage
+        });
+    }
+
+    render(&CoveragePage { roots: coverages })
+}
+
+// How many events each file had, by type, among those the search keeps.
+fn fetch_counts(
+    conn: &Connection,
+    search: &EventSearch
+) -> Result<Vec<(String, String, i64)>> {
+
+    let (filter, values) = search.clause();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT path.file_path, event.type_event, count(*) FROM {} {} GROUP BY event.path_id, event.type_event",
+        EVENT_TABLES, filter
+    ))?;
+    let rows = stmt.query_map(params_from_iter(&values), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+
+    rows.collect()
+}
+
+#[get("/tree")]
+async fn tree(data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Query<HashMap<String, String>>) -> impl Responder {
+    let search = EventSearch::from_query(&info);
+
+    let conn = match Connection::open(database(&data)) {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+    let counts = match fetch_counts(&conn, &search) {
+        Ok(counts) => counts,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    let mut presets = Vec::new();
+    for (label, modifier) in [("Last 24 hours", "-1 day"), ("Last 7 days", "-7 days"), ("Last 30 days", "-30 days")] {
+        let since: Result<String> = conn.query_row("SELECT strftime('%Y-%m-%dT%H:%M', 'now', ?1)", params![modifier], |row| row.get(0));
+        if let Ok(since) = since {
+            let preset = EventSearch { since: Some(since), until: None, ..search.clone() };
+            presets.push((label, format!("/tree?{}", preset.query_string())));
+        }
+    }
+
+    render(&TreePage {
+        search: &search,
+        types: EVENT_TYPES.iter().map(|type_event| (*type_event, search.type_event.as_deref() == Some(*type_event))).collect(),
+        presets,
+        rows: rollup(&counts)
+    })
+}
+
+#[get("/history")]
+async fn history(req: HttpRequest, data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Query<HashMap<String, String>>) -> impl Responder {
+    let csrf = auth::csrf_token(&req);
+    let path = match info.get("path") {
+        Some(p) => PathBuf::from(p),
+        None => return HttpResponse::Found().append_header(("Location", "/")).finish()
+    };
+
+    let conn = match Connection::open(database(&data)) {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+    let versions = match path_history(&conn, &path) {
+        Ok(versions) => versions,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
 
-    println!("Launch of the program...");
+    render(&HistoryPage {
+        csrf: &csrf,
+        path: &path.to_string_lossy(),
+        versions
+    })
+}
 
-    let app_fim_clone = Arc::clone(&app_fim_mutex);
-    let path_from_web = app_fim.path_from_web.clone();
-    thread::spawn(move || {
-        AppFIM::app(app_fim_clone, path_from_web).expect("Impossible to start app");
-    });
+#[get("/timeline")]
+async fn timeline(data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Query<HashMap<String, String>>) -> impl Responder {
+    let path = match info.get("path") {
+        Some(p) => p,
+        None => return HttpResponse::Found().append_header(("Location", "/")).finish()
+    };
+
+    let conn = match Connection::open(database(&data)) {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+    let entries = match fetch_timeline(&conn, path) {
+        Ok(entries) => entries,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    render(&TimelinePage {
+        path,
+        entries,
+        split: false
+    })
+}
+
+#[get("/version")]
+async fn download_version(data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Query<HashMap<String, String>>) -> impl Responder {
+    let conn = match Connection::open(database(&data)) {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    let found = if let Some(id) = info.get("id") {
+        match id.parse::<i64>() {
+            Ok(id) if info.contains_key("before") => version_before_event(&conn, id).map(|data| (id, data)),
+            Ok(id) => version_at_event(&conn, id).map(|data| (id, data)),
+            Err(_) => return HttpResponse::BadRequest().body("invalid id")
+        }
+    } else if let (Some(path), Some(at)) = (info.get("path"), info.get("at")) {
+        version_at_time(&conn, Path::new(path), at)
+    } else {
+        return HttpResponse::BadRequest().body("expected id, or path and at");
+    };
+
+    let (id, data) = match found {
+        Ok(found) => found,
+        Err(e) => return HttpResponse::NotFound().body(e.to_string())
+    };
+
+    let file_name: Option<String> = conn.query_row(
+        "SELECT path.file_path FROM event INNER JOIN path ON event.path_id = path.id WHERE event.id = ?1",
+        params![id],
+        |row| row.get(0)
+    ).ok();
+    let file_name = file_name
+        .as_deref()
+        .and_then(|p| Path::new(p).file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from("version"));
+
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .append_header(("Content-Disposition", format!("attachment; filename=\"{}.{}\"", file_name.replace('"', ""), id)))
+        .append_header(("X-FIM-Event-Id", id.to_string()))
+        .body(data)
+}
+
+#[post("/restore")]
+async fn restore(req: HttpRequest, data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Form<HashMap<String, String>>) -> impl Responder {
+    if let Err(response) = auth::verify_csrf(&req, info.get("csrf")) {
+        return response;
+    }
+
+    let id = match info.get("id").map(|id| id.parse::<i64>()) {
+        Some(Ok(id)) => id,
+        _ => return HttpResponse::BadRequest().body("invalid id")
+    };
+
+    let conn = match Connection::open(database(&data)) {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    let user = req.extensions().get::<User>().map(|user| user.name.clone()).unwrap_or_default();
+    let operator = match req.peer_addr() {
+        Some(addr) => format!("web ({}, {})", user, addr.ip()),
+        None => format!("web ({})", user)
+    };
+
+    match restore_version(&conn, id, info.contains_key("before"), &operator) {
+        Ok(path) => {
+            let location = format!("/history?path={}", utf8_percent_encode(&path.to_string_lossy(), NON_ALPHANUMERIC));
+            HttpResponse::Found().append_header(("Location", location)).finish()
+        },
+        Err(e) => HttpResponse::InternalServerError().body(format!("restore failed: {}", e))
+    }
+}
+
+#[post("/start")]
+async fn start(req: HttpRequest, data: web::Data<Arc<Mutex<AppFIM>>>, form: web::Form<HashMap<String, String>>) -> impl Responder {
+    if let Err(response) = auth::verify_csrf(&req, form.get("csrf")) {
+        return response;
+    }
+
+    AppFIM::start(data.get_ref());
 
     HttpResponse::Found().append_header(("Location", "/")).finish()
 }
 
-#[get("/stop")]
-async fn stop(data: web::Data<Arc<Mutex<AppFIM>>>) -> impl Responder {
-    let app_fim_mutex = data.get_ref();
-    let mut app_fim = app_fim_mutex.lock().unwrap();
-    app_fim.state = false;
+#[post("/stop")]
+async fn stop(req: HttpRequest, data: web::Data<Arc<Mutex<AppFIM>>>, form: web::Form<HashMap<String, String>>) -> impl Responder {
+    if let Err(response) = auth::verify_csrf(&req, form.get("csrf")) {
+        return response;
+    }
 
-    println!("Stopping the program...");
+    data.get_ref().lock().unwrap().stop();
 
     HttpResponse::Found().append_header(("Location", "/")).finish()
 }
 
-#[get("/add")]
-async fn add(data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Query<HashMap<String, String>>) -> impl Responder {
+#[post("/add")]
+async fn add(req: HttpRequest, data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Form<HashMap<String, String>>) -> impl Responder {
+    if let Err(response) = auth::verify_csrf(&req, info.get("csrf")) {
+        return response;
+    }
+
     let app_fim_mutex = data.get_ref();
     let mut app_fim = app_fim_mutex.lock().unwrap();
     
-    if app_fim.state == true {
+    if app_fim.state {
         return HttpResponse::Found().append_header(("Location", "/")).finish();
     }
 
-    let path = match info.get("path") {
-        Some(p) => p,
-        None => return HttpResponse::Found().append_header(("Location", "/")).finish()
-    };
-
-    match check_path(&path) {
-        Err(_) => return HttpResponse::Found().append_header(("Location", "/")).finish(),
-        _ => (),
+    if let Some(path) = info.get("path") {
+        let _ = app_fim.add_root(path);
     }
 
-    let desired_path = match std::fs::canonicalize(path) {
-        Ok(p) => p,
-        Err(_) => return HttpResponse::Found().append_header(("Location", "/")).finish()
-    };
+    HttpResponse::Found().append_header(("Location", "/")).finish()
+}
 
-    if !app_fim.path_from_web.contains(&desired_path) {
-        let mut to_delete: Vec<PathBuf> = Vec::new();
+#[post("/del")]
+async fn del(req: HttpRequest, data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Form<HashMap<String, String>>) -> impl Responder {
+    if let Err(response) = auth::verify_csrf(&req, info.get("csrf")) {
+        return response;
+    }
 
-        for item in &app_fim.path_from_web {
-            if desired_path.starts_with(&item) {
-                return HttpResponse::Found().append_header(("Location", "/")).finish();
-            }
-            if item.starts_with(&desired_path) {
-                to_delete.push(item.to_path_buf());
-            }
-        }
+    let app_fim_mutex = data.get_ref();
+    let mut app_fim = app_fim_mutex.lock().unwrap();
 
-        for item in &to_delete {
-            app_fim.path_from_web.retain(|x| *x != PathBuf::from(item));
-        }
+    if app_fim.state {
+        return HttpResponse::Found().append_header(("Location", "/")).finish();
+    }
 
-        app_fim.path_from_web.push(desired_path);
+    if let Some(path) = info.get("path") {
+        app_fim.remove_root(Path::new(path));
     }
 
     HttpResponse::Found().append_header(("Location", "/")).finish()
 }
 
-#[get("/del")]
-async fn del(data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Query<HashMap<String, String>>) -> impl Responder {
+fn parse_rules(
+    text: Option<&String>
+) -> Vec<String> {
+    match text {
+        Some(text) => text.lines()
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(String::from)
+            .collect(),
+        None => Vec::new()
+    }
+}
+
+#[post("/rules")]
+async fn set_rules(req: HttpRequest, data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Form<HashMap<String, String>>) -> impl Responder {
+    if let Err(response) = auth::verify_csrf(&req, info.get("csrf")) {
+        return response;
+    }
+
     let app_fim_mutex = data.get_ref();
     let mut app_fim = app_fim_mutex.lock().unwrap();
 
-    if app_fim.state == true {
+    if app_fim.state {
         return HttpResponse::Found().append_header(("Location", "/")).finish();
     }
 
@@ -259,24 +885,134 @@ async fn del(data: web::Data<Arc<Mutex<AppFIM>>>, info: web::Query<HashMap<Strin
         None => return HttpResponse::Found().append_header(("Location", "/")).finish()
     };
 
-    app_fim.path_from_web.retain(|x| *x != PathBuf::from(path));
+    if !app_fim.config.roots.iter().any(|root| root.path.as_path() == Path::new(path)) {
+        return HttpResponse::Found().append_header(("Location", "/")).finish();
+    }
+    if let Err(e) = app_fim.set_rules(Path::new(path), parse_rules(info.get("include")), parse_rules(info.get("exclude"))) {
+        return HttpResponse::BadRequest().body(e);
+    }
 
     HttpResponse::Found().append_header(("Location", "/")).finish()
 }
 
+fn login_page(error: &str) -> HttpResponse {
+    let mut response = render(&LoginPage { error });
+    if !error.is_empty() && response.status().is_success() {
+        *response.status_mut() = actix_web::http::StatusCode::UNAUTHORIZED;
+    }
+    response
+}
+
+#[get("/login")]
+async fn login_form() -> impl Responder {
+    login_page("")
+}
+
+#[post("/login")]
+async fn login(data: web::Data<Arc<Mutex<AppFIM>>>, form: web::Form<HashMap<String, String>>) -> impl Responder {
+    let conn = match Connection::open(database(&data)) {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    let name = form.get("name").map(String::as_str).unwrap_or("");
+    let password = form.get("password").map(String::as_str).unwrap_or("");
+    match auth::login(&conn, name, password) {
+        Ok(Some(token)) => {
+            println!("Connexion de {}", name);
+            HttpResponse::Found()
+                .cookie(auth::session_cookie(&token))
+                .append_header(("Location", "/"))
+                .finish()
+        },
+        Ok(None) => {
+            println!("Échec de connexion pour {}", name);
+            login_page("Wrong user or password")
+        },
+        Err(_) => HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[post("/logout")]
+async fn logout(req: HttpRequest, data: web::Data<Arc<Mutex<AppFIM>>>, form: web::Form<HashMap<String, String>>) -> impl Responder {
+    if let Err(response) = auth::verify_csrf(&req, form.get("csrf")) {
+        return response;
+    }
+
+    if let Some(cookie) = req.cookie(auth::COOKIE_NAME) {
+        if let Ok(conn) = Connection::open(database(&data)) {
+            let _ = auth::logout(&conn, cookie.value());
+        }
+    }
+
+    HttpResponse::Found()
+        .cookie(auth::removal_cookie())
+        .append_header(("Location", "/login"))
+        .finish()
+}
+
 #[actix_web::main]
 pub async fn start_web(app_fim: Arc<Mutex<AppFIM>>) -> std::io::Result<()> {
+    let bind = app_fim.lock().unwrap().config.web.bind.clone();
+    println!("Interface web : http://{}", bind);
+
     HttpServer::new(move || {
         App::new()
         .app_data(web::Data::new(Arc::clone(&app_fim)))
+        // Every page and API call except the login page needs a signed-in
+        // user.
+        .wrap_fn(|req, srv| {
+            let checked = match auth::check(&req) {
+                Ok(()) => Ok(srv.call(req)),
+                Err(response) => Err(req.into_response(response))
+            };
+            async move {
+                match checked {
+                    Ok(response) => Ok(response.await?.map_into_left_body()),
+                    Err(response) => Ok(response.map_into_right_body())
+                }
+            }
+        })
+        .service(login_form)
+        .service(login)
+        .service(logout)
         .service(start)
         .service(stop)
         .service(index)
+        .service(live_stream)
         .service(add)
         .service(del)
+        .service(set_rules)
         .service(diffweb)
+        .service(history)
+        .service(timeline)
+        .service(coverage)
+        .service(tree)
+        .service(download_version)
+        .service(restore)
+        .service(api::scope())
     })
-    .bind("127.0.0.1:6077")?
+    .bind(bind)?
     .run()
     .await
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn any_page_number_can_be_asked_for() {
+        let conn = testing::database();
+        conn.execute("INSERT INTO path (file_path) VALUES ('/srv/a')", []).unwrap();
+        conn.execute("INSERT INTO event (type_event, path_id) VALUES ('CREATE', 1)", []).unwrap();
+
+        assert_eq!(page_offset(50_000_000), 4_999_999_900);
+        let (events, total) = fetch_events(&conn, &EventSearch::default(), 1).unwrap();
+        assert_eq!((events.len(), total), (1, 1));
+        for page in [2, 50_000_000, u32::MAX] {
+            let (events, total) = fetch_events(&conn, &EventSearch::default(), page).unwrap();
+            assert_eq!((events.len(), total), (0, 1));
+        }
+    }
+}
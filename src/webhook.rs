@@ -0,0 +1,323 @@
+use std::path::PathBuf;
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use rusqlite::{params, Connection};
+
+use crate::config::WebhookConfig;
+use crate::sink::EventRecord;
+
+
+
+const SINK: &str = "webhook";
+
+// Retry delays double from the first one up to the last.
+const FIRST_RETRY_SECONDS: i64 = 5;
+const MAX_RETRY_SECONDS: i64 = 3600;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Delivery {
+    id: i64,
+    event_id: i64,
+    payload: String,
+    attempts: u32
+}
+
+pub fn enqueue(
+    conn: &Connection,
+    record: &EventRecord
+) -> Result<(), Box<dyn Error>> {
+
+    conn.execute(
+        "INSERT INTO outbox (sink, event_id, payload, next_attempt_at) VALUES (?1, ?2, ?3, strftime('%s', 'now'))",
+        params![SINK, record.id, serde_json::to_string(record)?],
+    )?;
+
+    Ok(())
+}
+
+fn sign(
+    secret: &str,
+    body: &str
+) -> Result<String, Box<dyn Error>> {
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(body.as_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    Ok(format!("sha256={}", digest.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()))
+}
+
+fn retry_delay(attempts: u32) -> i64 {
+    FIRST_RETRY_SECONDS
+        .saturating_mul(1i64 << attempts.saturating_sub(1).min(20))
+        .min(MAX_RETRY_SECONDS)
+}
+
+fn post(
+    config: &WebhookConfig,
+    delivery: &Delivery
+) -> Result<(), String> {
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(config.timeout_seconds))
+        .build();
+    let mut request = agent.post(&config.url)
+        .set("Content-Type", "application/json")
+        .set("User-Agent", concat!("fileintegritymonitoring/", env!("CARGO_PKG_VERSION")))
+        .set("X-FIM-Event-Id", &delivery.event_id.to_string())
+        .set("X-FIM-Delivery", &delivery.id.to_string())
+        .set("X-FIM-Attempt", &(delivery.attempts + 1).to_string());
+    if let Some(secret) = &config.secret {
+        let signature = sign(secret, &delivery.payload).map_err(|e| e.to_string())?;
+        request = request.set("X-FIM-Signature", &signature);
+    }
+
+    match request.send_string(&delivery.payload) {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(code, _)) => Err(format!("HTTP {}", code)),
+        Err(e) => Err(e.to_string())
+    }
+}
+
+// Sends every delivery that is due, oldest first. Each one is claimed by
+// pushing its next attempt past the request timeout, so two processes
+// sharing the database never send it twice.
+pub fn deliver_pending(
+    conn: &Connection,
+    config: &WebhookConfig
+) -> Result<usize, Box<dyn Error>> {
+
+    let mut stmt = conn.prepare(
+        "SELECT id, event_id, payload, attempts FROM outbox
+        WHERE sink = ?1 AND status = 'PENDING' AND next_attempt_at <= strftime('%s', 'now')
+        ORDER BY id LIMIT 100"
+    )?;
+    let due = stmt.query_map(params![SINK], |row| {
+        Ok(Delivery {
+            id: row.get(0)?,
+            event_id: row.get(1)?,
+            payload: row.get(2)?,
+            attempts: row.get(3)?
+        })
+    })?.collect::<Result<Vec<_>, _>>()?;
+
+    let mut delivered = 0;
+    for delivery in due {
+        let claimed = conn.execute(
+            "UPDATE outbox SET next_attempt_at = strftime('%s', 'now') + ?1
+            WHERE id = ?2 AND status = 'PENDING' AND next_attempt_at <= strftime('%s', 'now')",
+            params![config.timeout_seconds as i64 * 2, delivery.id],
+        )?;
+        if claimed == 0 {
+            continue;
+        }
+
+        match post(config, &delivery) {
+            Ok(()) => {
+                conn.execute(
+                    "UPDATE outbox SET status = 'DELIVERED', attempts = attempts + 1, delivered_at = CURRENT_TIMESTAMP, last_error = NULL WHERE id = ?1",
+                    params![delivery.id],
+                )?;
+                delivered += 1;
+            }
+            Err(e) => {
+                let attempts = delivery.attempts + 1;
+                let status = if attempts >= config.max_attempts { "FAILED" } else { "PENDING" };
+                println!("Webhook : événement {} non envoyé (tentative {}) : {}", delivery.event_id, attempts, e);
+                conn.execute(
+                    "UPDATE outbox SET status = ?1, attempts = ?2, last_error = ?3, next_attempt_at = strftime('%s', 'now') + ?4 WHERE id = ?5",
+                    params![status, attempts, e, retry_delay(attempts), delivery.id],
+                )?;
+            }
+        }
+    }
+
+    Ok(delivered)
+}
+
+// Runs for the life of the process; what is left in the outbox when it
+// exits is sent by the next one.
+pub fn spawn(
+    database: PathBuf,
+    config: WebhookConfig
+) {
+    thread::spawn(move || loop {
+        let result = Connection::open(&database)
+            .map_err(|e| e.into())
+            .and_then(|conn| deliver_pending(&conn, &config));
+        if let Err(e) = result {
+            println!("Webhook : {}", e);
+        }
+        thread::sleep(POLL_INTERVAL);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use crate::app::AppFIM;
+    use crate::testing;
+
+    struct Request {
+        headers: Vec<(String, String)>,
+        body: String
+    }
+
+    impl Request {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    // A local HTTP server answering each request with the next status, and
+    // handing over what it received.
+    fn stand_in(statuses: Vec<u16>) -> (String, Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut headers = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((key, value)) = line.split_once(':') {
+                        headers.push((key.trim().to_string(), value.trim().to_string()));
+                    }
+                }
+                let request = Request { headers, body: String::new() };
+                let length = request.header("Content-Length").and_then(|length| length.parse().ok()).unwrap_or(0);
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                write!(reader.get_mut(), "HTTP/1.1 {} Test\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+                sender.send(Request { body: String::from_utf8(body).unwrap(), ..request }).unwrap();
+            }
+        });
+
+        (url, receiver)
+    }
+
+    fn config(url: String) -> WebhookConfig {
+        WebhookConfig {
+            url,
+            secret: Some(String::from("secret")),
+            types: Vec::new(),
+            paths: Vec::new(),
+            max_attempts: 12,
+            timeout_seconds: 5
+        }
+    }
+
+    // Status, attempts and seconds until the next attempt of a delivery.
+    fn state(conn: &Connection) -> (String, u32, i64) {
+        conn.query_row(
+            "SELECT status, attempts, next_attempt_at - strftime('%s', 'now') FROM outbox",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        ).unwrap()
+    }
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn delivers_the_event_as_signed_json() {
+        let conn = testing::database();
+        let (url, requests) = stand_in(vec![200]);
+        enqueue(&conn, &testing::record()).unwrap();
+
+        assert_eq!(deliver_pending(&conn, &config(url)).unwrap(), 1);
+
+        let request = requests.recv().unwrap();
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["id"], 42);
+        assert_eq!(body["type"], "MODIFY");
+        assert_eq!(body["path"], "/srv/www/index.php");
+        assert_eq!(request.header("Content-Type"), Some("application/json"));
+        assert_eq!(request.header("X-FIM-Event-Id"), Some("42"));
+
+        let signature = request.header("X-FIM-Signature").unwrap().strip_prefix("sha256=").unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(request.body.as_bytes());
+        assert!(mac.verify_slice(&hex(signature)).is_ok());
+        assert_eq!(state(&conn).0, "DELIVERED");
+    }
+
+    #[test]
+    fn failures_are_retried_later_and_later() {
+        let conn = testing::database();
+        let (url, requests) = stand_in(vec![500, 500, 200]);
+        let config = config(url);
+        enqueue(&conn, &testing::record()).unwrap();
+
+        assert_eq!(deliver_pending(&conn, &config).unwrap(), 0);
+        let (status, attempts, delay) = state(&conn);
+        assert_eq!((status.as_str(), attempts), ("PENDING", 1));
+        assert!((4..=5).contains(&delay));
+
+        // Not due yet: nothing is sent.
+        assert_eq!(deliver_pending(&conn, &config).unwrap(), 0);
+        assert_eq!(state(&conn).1, 1);
+
+        conn.execute("UPDATE outbox SET next_attempt_at = strftime('%s', 'now')", []).unwrap();
+        deliver_pending(&conn, &config).unwrap();
+        let (_, attempts, delay) = state(&conn);
+        assert_eq!(attempts, 2);
+        assert!((9..=10).contains(&delay));
+
+        conn.execute("UPDATE outbox SET next_attempt_at = strftime('%s', 'now')", []).unwrap();
+        assert_eq!(deliver_pending(&conn, &config).unwrap(), 1);
+        assert_eq!(requests.iter().take(3).map(|request| request.header("X-FIM-Attempt").unwrap().to_string()).collect::<Vec<_>>(), ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn retry_delays_double_up_to_an_hour() {
+        let delays: Vec<i64> = (1..=12).map(retry_delay).collect();
+
+        assert_eq!(delays[..6], [5, 10, 20, 40, 80, 160]);
+        assert_eq!(delays[10..], [3600, 3600]);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_SECONDS);
+    }
+
+    #[test]
+    fn the_last_attempt_gives_up() {
+        let conn = testing::database();
+        let (url, _requests) = stand_in(vec![500]);
+        let config = WebhookConfig { max_attempts: 1, ..config(url) };
+        enqueue(&conn, &testing::record()).unwrap();
+
+        deliver_pending(&conn, &config).unwrap();
+
+        assert_eq!(state(&conn).0, "FAILED");
+    }
+
+    #[test]
+    fn the_outbox_survives_a_restart() {
+        let database = testing::directory("outbox").join("database.db");
+        AppFIM::init_db(&database).unwrap();
+        enqueue(&Connection::open(&database).unwrap(), &testing::record()).unwrap();
+
+        let (url, requests) = stand_in(vec![200]);
+        let conn = Connection::open(&database).unwrap();
+        assert_eq!(deliver_pending(&conn, &config(url)).unwrap(), 1);
+        assert_eq!(requests.recv().unwrap().header("X-FIM-Event-Id"), Some("42"));
+    }
+}